@@ -0,0 +1,201 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tokio_postgres::GenericClient;
+
+use crate::rpc::{self, Routine};
+
+/// Tables, views and their columns, with one row per column.
+const COLUMNS_QUERY: &str = "
+    SELECT n.nspname,
+           c.relname,
+           c.relkind::text,
+           a.attname,
+           format_type(a.atttypid, NULL) AS type_name,
+           NOT a.attnotnull AS nullable,
+           coalesce(a.attnum = ANY(pk.indkey), false) AS is_primary_key
+    FROM pg_class c
+    JOIN pg_namespace n ON n.oid = c.relnamespace
+    JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum > 0 AND NOT a.attisdropped
+    LEFT JOIN pg_index pk ON pk.indrelid = c.oid AND pk.indisprimary
+    WHERE c.relkind IN ('r', 'p', 'v', 'm', 'f')
+      AND NOT c.relispartition
+      AND n.nspname <> 'information_schema'
+      AND n.nspname NOT LIKE 'pg\\_%'
+      AND ($1::text[] IS NULL OR n.nspname = ANY($1))
+    ORDER BY n.nspname, c.relname, a.attnum";
+
+/// Foreign keys with their columns in constraint order.
+pub const FOREIGN_KEYS_QUERY: &str = "
+    SELECT c.conname::text,
+           n.nspname::text,
+           t.relname::text,
+           ARRAY(
+               SELECT a.attname::text
+               FROM unnest(c.conkey) WITH ORDINALITY AS k(attnum, i)
+               JOIN pg_attribute a ON a.attrelid = c.conrelid AND a.attnum = k.attnum
+               ORDER BY k.i
+           ) AS columns,
+           rn.nspname::text AS referenced_schema,
+           r.relname::text AS referenced_table,
+           ARRAY(
+               SELECT a.attname::text
+               FROM unnest(c.confkey) WITH ORDINALITY AS k(attnum, i)
+               JOIN pg_attribute a ON a.attrelid = c.confrelid AND a.attnum = k.attnum
+               ORDER BY k.i
+           ) AS referenced_columns
+    FROM pg_constraint c
+    JOIN pg_class t ON t.oid = c.conrelid
+    JOIN pg_namespace n ON n.oid = t.relnamespace
+    JOIN pg_class r ON r.oid = c.confrelid
+    JOIN pg_namespace rn ON rn.oid = r.relnamespace
+    WHERE c.contype = 'f' AND c.conparentid = 0
+    ORDER BY n.nspname, t.relname, c.conname";
+
+/// The tables, views and functions exposed through the generated endpoints, loaded at
+/// startup.
+#[derive(Debug, Default)]
+pub struct Catalog {
+    tables: BTreeMap<(String, String), Table>,
+    functions: Vec<Routine>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub schema: String,
+    pub name: String,
+    pub kind: TableKind,
+    /// Columns in table order
+    pub columns: Vec<Column>,
+    /// Primary key columns, empty for views and tables without a primary key
+    pub primary_key: Vec<String>,
+    /// Foreign keys to other tables in the catalog
+    pub foreign_keys: Vec<ForeignKey>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TableKind {
+    Table,
+    View,
+    MaterializedView,
+    ForeignTable,
+}
+
+impl TableKind {
+    /// Maps `pg_class.relkind`; partitioned tables are tables.
+    pub fn from_relkind(relkind: &str) -> Self {
+        match relkind {
+            "v" => TableKind::View,
+            "m" => TableKind::MaterializedView,
+            "f" => TableKind::ForeignTable,
+            _ => TableKind::Table,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    /// Type as accepted in a cast, without modifiers, e.g. `character varying`
+    pub type_name: String,
+    pub nullable: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ForeignKey {
+    /// Constraint name
+    pub name: String,
+    pub columns: Vec<String>,
+    pub referenced_schema: String,
+    pub referenced_table: String,
+    /// Referenced columns, in the same order as `columns`
+    pub referenced_columns: Vec<String>,
+}
+
+impl Catalog {
+    /// Introspects the tables, views and functions of `schemas`, or of all non-system
+    /// schemas.
+    pub async fn load<C: GenericClient>(
+        client: &C,
+        schemas: Option<&[String]>,
+    ) -> Result<Self, tokio_postgres::Error> {
+        let schemas = schemas.map(<[String]>::to_vec);
+        let rows = client.query(COLUMNS_QUERY, &[&schemas]).await?;
+
+        let mut tables: BTreeMap<(String, String), Table> = BTreeMap::new();
+        for row in rows {
+            let schema: String = row.get("nspname");
+            let name: String = row.get("relname");
+            let table = tables
+                .entry((schema.clone(), name.clone()))
+                .or_insert_with(|| Table {
+                    schema,
+                    name,
+                    kind: TableKind::from_relkind(row.get("relkind")),
+                    columns: Vec::new(),
+                    primary_key: Vec::new(),
+                    foreign_keys: Vec::new(),
+                });
+
+            let column = Column {
+                name: row.get("attname"),
+                type_name: row.get("type_name"),
+                nullable: row.get("nullable"),
+            };
+            if row.get("is_primary_key") {
+                table.primary_key.push(column.name.clone());
+            }
+            table.columns.push(column);
+        }
+
+        // Only relationships between exposed tables are kept
+        for row in client.query(FOREIGN_KEYS_QUERY, &[]).await? {
+            let foreign_key = ForeignKey {
+                name: row.get("conname"),
+                columns: row.get("columns"),
+                referenced_schema: row.get("referenced_schema"),
+                referenced_table: row.get("referenced_table"),
+                referenced_columns: row.get("referenced_columns"),
+            };
+            let referenced = (
+                foreign_key.referenced_schema.clone(),
+                foreign_key.referenced_table.clone(),
+            );
+            if !tables.contains_key(&referenced) {
+                continue;
+            }
+            if let Some(table) = tables.get_mut(&(row.get("nspname"), row.get("relname"))) {
+                table.foreign_keys.push(foreign_key);
+            }
+        }
+
+        let functions = rpc::list(client, schemas.as_deref()).await?;
+        Ok(Self { tables, functions })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    pub fn table(&self, schema: &str, name: &str) -> Option<&Table> {
+        self.tables.get(&(schema.to_string(), name.to_string()))
+    }
+
+    pub fn tables(&self) -> impl Iterator<Item = &Table> {
+        self.tables.values()
+    }
+
+    pub fn functions(&self) -> &[Routine] {
+        &self.functions
+    }
+}
+
+impl Table {
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.columns.iter().find(|column| column.name == name)
+    }
+}
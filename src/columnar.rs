@@ -0,0 +1,410 @@
+use anyhow::{anyhow, Result};
+use arrow::array::{
+    ArrayRef, BinaryBuilder, BooleanBuilder, Date32Builder, Decimal128Builder, Float32Builder,
+    Float64Builder, Int16Builder, Int32Builder, Int64Builder, StringBuilder,
+    Time64MicrosecondBuilder, TimestampMicrosecondBuilder, UInt32Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit, DECIMAL128_MAX_PRECISION};
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use bytes::BytesMut;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio_postgres::types::{FromSql, Type};
+use tokio_postgres::{Column, Row};
+use tracing::warn;
+
+use crate::format::RowEncoder;
+use crate::types;
+
+pub const ARROW_STREAM_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+pub const PARQUET_CONTENT_TYPE: &str = "application/vnd.apache.parquet";
+
+/// Rows collected into each record batch.
+const BATCH_SIZE: usize = 8192;
+/// Rows per Parquet row group; the writer holds a row group in memory until it is full.
+const PARQUET_ROW_GROUP_SIZE: usize = 64 * 1024;
+const UTC: &str = "UTC";
+
+/// Builds the Arrow schema for a result from its column types.
+pub fn schema_for(columns: &[Column]) -> Schema {
+    Schema::new(
+        columns
+            .iter()
+            .map(|column| Field::new(column.name(), data_type(column), true))
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Maps a column's PostgreSQL type to an Arrow type. Types without a native Arrow
+/// counterpart are written as strings, the same text `/query` returns in JSON.
+fn data_type(column: &Column) -> DataType {
+    match *column.type_() {
+        Type::BOOL => DataType::Boolean,
+        Type::INT2 => DataType::Int16,
+        Type::INT4 => DataType::Int32,
+        Type::INT8 => DataType::Int64,
+        Type::OID => DataType::UInt32,
+        Type::FLOAT4 => DataType::Float32,
+        Type::FLOAT8 => DataType::Float64,
+        Type::NUMERIC => match numeric_precision_scale(column.type_modifier()) {
+            Some((precision, scale)) => DataType::Decimal128(precision, scale),
+            // Unconstrained numerics have no fixed scale, so keep their exact text
+            None => DataType::Utf8,
+        },
+        Type::DATE => DataType::Date32,
+        Type::TIME => DataType::Time64(TimeUnit::Microsecond),
+        Type::TIMESTAMP => DataType::Timestamp(TimeUnit::Microsecond, None),
+        Type::TIMESTAMPTZ => DataType::Timestamp(TimeUnit::Microsecond, Some(UTC.into())),
+        Type::BYTEA => DataType::Binary,
+        _ => DataType::Utf8,
+    }
+}
+
+/// Extracts precision and scale from a `numeric(p, s)` type modifier.
+fn numeric_precision_scale(type_modifier: i32) -> Option<(u8, i8)> {
+    // VARHDRSZ is added to the modifier; -1 means no modifier
+    let modifier = type_modifier.checked_sub(4).filter(|m| *m >= 0)?;
+    let precision = (modifier >> 16) & 0xffff;
+    let scale = modifier & 0xffff;
+    if precision == 0 || precision > DECIMAL128_MAX_PRECISION as i32 || scale > precision {
+        return None;
+    }
+    Some((precision as u8, scale as i8))
+}
+
+/// Parses an exact decimal string into an `i128` scaled by `10^scale`.
+fn parse_decimal(value: &str, scale: i8) -> Option<i128> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value),
+    };
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let scale = scale as usize;
+    if fraction.len() > scale
+        || integer.is_empty()
+        || !integer
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let unscaled = format!(
+        "{}{}{}",
+        integer,
+        fraction,
+        "0".repeat(scale - fraction.len())
+    );
+    let unscaled: i128 = unscaled.parse().ok()?;
+    Some(if negative { -unscaled } else { unscaled })
+}
+
+/// Reads a column with a native decoder, logging and returning NULL if it cannot be decoded.
+fn get<'a, T: FromSql<'a>>(row: &'a Row, idx: usize) -> Option<T> {
+    match row.try_get::<_, Option<T>>(idx) {
+        Ok(value) => value,
+        Err(e) => {
+            let column = &row.columns()[idx];
+            warn!(
+                "Failed to decode column {} of type {}: {}",
+                column.name(),
+                column.type_(),
+                e
+            );
+            None
+        }
+    }
+}
+
+fn text_value(row: &Row, idx: usize) -> Option<String> {
+    match types::column_value(row, idx) {
+        Value::Null => None,
+        Value::String(s) => Some(s),
+        other => Some(other.to_string()),
+    }
+}
+
+enum ColumnBuilder {
+    Boolean(BooleanBuilder),
+    Int16(Int16Builder),
+    Int32(Int32Builder),
+    Int64(Int64Builder),
+    UInt32(UInt32Builder),
+    Float32(Float32Builder),
+    Float64(Float64Builder),
+    Decimal(Decimal128Builder, i8),
+    Date(Date32Builder),
+    Time(Time64MicrosecondBuilder),
+    Timestamp(TimestampMicrosecondBuilder),
+    TimestampTz(TimestampMicrosecondBuilder),
+    Binary(BinaryBuilder),
+    Utf8(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(data_type: &DataType) -> Result<Self> {
+        Ok(match data_type {
+            DataType::Boolean => ColumnBuilder::Boolean(BooleanBuilder::new()),
+            DataType::Int16 => ColumnBuilder::Int16(Int16Builder::new()),
+            DataType::Int32 => ColumnBuilder::Int32(Int32Builder::new()),
+            DataType::Int64 => ColumnBuilder::Int64(Int64Builder::new()),
+            DataType::UInt32 => ColumnBuilder::UInt32(UInt32Builder::new()),
+            DataType::Float32 => ColumnBuilder::Float32(Float32Builder::new()),
+            DataType::Float64 => ColumnBuilder::Float64(Float64Builder::new()),
+            DataType::Decimal128(precision, scale) => ColumnBuilder::Decimal(
+                Decimal128Builder::new().with_precision_and_scale(*precision, *scale)?,
+                *scale,
+            ),
+            DataType::Date32 => ColumnBuilder::Date(Date32Builder::new()),
+            DataType::Time64(_) => ColumnBuilder::Time(Time64MicrosecondBuilder::new()),
+            DataType::Timestamp(_, None) => {
+                ColumnBuilder::Timestamp(TimestampMicrosecondBuilder::new())
+            }
+            DataType::Timestamp(_, Some(_)) => {
+                ColumnBuilder::TimestampTz(TimestampMicrosecondBuilder::new().with_timezone(UTC))
+            }
+            DataType::Binary => ColumnBuilder::Binary(BinaryBuilder::new()),
+            DataType::Utf8 => ColumnBuilder::Utf8(StringBuilder::new()),
+            other => return Err(anyhow!("Unsupported Arrow type {}", other)),
+        })
+    }
+
+    fn append(&mut self, row: &Row, idx: usize) {
+        match self {
+            ColumnBuilder::Boolean(b) => b.append_option(get::<bool>(row, idx)),
+            ColumnBuilder::Int16(b) => b.append_option(get::<i16>(row, idx)),
+            ColumnBuilder::Int32(b) => b.append_option(get::<i32>(row, idx)),
+            ColumnBuilder::Int64(b) => b.append_option(get::<i64>(row, idx)),
+            ColumnBuilder::UInt32(b) => b.append_option(get::<u32>(row, idx)),
+            ColumnBuilder::Float32(b) => b.append_option(get::<f32>(row, idx)),
+            ColumnBuilder::Float64(b) => b.append_option(get::<f64>(row, idx)),
+            // NaN and infinities have no decimal representation and are written as NULL
+            ColumnBuilder::Decimal(b, scale) => b.append_option(
+                text_value(row, idx).and_then(|value| parse_decimal(&value, *scale)),
+            ),
+            ColumnBuilder::Date(b) => b.append_option(
+                get::<NaiveDate>(row, idx)
+                    .map(|date| (date - NaiveDate::default()).num_days() as i32),
+            ),
+            ColumnBuilder::Time(b) => b.append_option(get::<NaiveTime>(row, idx).map(|time| {
+                time.num_seconds_from_midnight() as i64 * 1_000_000
+                    + time.nanosecond() as i64 / 1_000
+            })),
+            ColumnBuilder::Timestamp(b) => b.append_option(
+                get::<NaiveDateTime>(row, idx).map(|ts| ts.and_utc().timestamp_micros()),
+            ),
+            ColumnBuilder::TimestampTz(b) => {
+                b.append_option(get::<DateTime<Utc>>(row, idx).map(|ts| ts.timestamp_micros()))
+            }
+            ColumnBuilder::Binary(b) => b.append_option(get::<&[u8]>(row, idx)),
+            ColumnBuilder::Utf8(b) => b.append_option(text_value(row, idx)),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::Boolean(b) => Arc::new(b.finish()),
+            ColumnBuilder::Int16(b) => Arc::new(b.finish()),
+            ColumnBuilder::Int32(b) => Arc::new(b.finish()),
+            ColumnBuilder::Int64(b) => Arc::new(b.finish()),
+            ColumnBuilder::UInt32(b) => Arc::new(b.finish()),
+            ColumnBuilder::Float32(b) => Arc::new(b.finish()),
+            ColumnBuilder::Float64(b) => Arc::new(b.finish()),
+            ColumnBuilder::Decimal(b, _) => Arc::new(b.finish()),
+            ColumnBuilder::Date(b) => Arc::new(b.finish()),
+            ColumnBuilder::Time(b) => Arc::new(b.finish()),
+            ColumnBuilder::Timestamp(b) => Arc::new(b.finish()),
+            ColumnBuilder::TimestampTz(b) => Arc::new(b.finish()),
+            ColumnBuilder::Binary(b) => Arc::new(b.finish()),
+            ColumnBuilder::Utf8(b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+/// Collects rows into record batches.
+struct BatchBuilder {
+    schema: SchemaRef,
+    columns: Vec<ColumnBuilder>,
+    rows: usize,
+}
+
+impl BatchBuilder {
+    fn new(schema: SchemaRef) -> Result<Self> {
+        let columns = schema
+            .fields()
+            .iter()
+            .map(|field| ColumnBuilder::new(field.data_type()))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            schema,
+            columns,
+            rows: 0,
+        })
+    }
+
+    fn append(&mut self, row: &Row) {
+        for (idx, column) in self.columns.iter_mut().enumerate() {
+            column.append(row, idx);
+        }
+        self.rows += 1;
+    }
+
+    fn finish(&mut self) -> Result<RecordBatch> {
+        let arrays = self.columns.iter_mut().map(ColumnBuilder::finish).collect();
+        self.rows = 0;
+        Ok(RecordBatch::try_new(self.schema.clone(), arrays)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnarFormat {
+    ArrowStream,
+    Parquet,
+}
+
+enum Writer {
+    ArrowStream(StreamWriter<Vec<u8>>),
+    Parquet(ArrowWriter<Vec<u8>>),
+}
+
+impl Writer {
+    fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        match self {
+            Writer::ArrowStream(w) => w.write(batch)?,
+            Writer::Parquet(w) => w.write(batch)?,
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self {
+            Writer::ArrowStream(w) => w.finish()?,
+            Writer::Parquet(w) => {
+                w.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves everything written so far into `out`.
+    fn drain_into(&mut self, out: &mut BytesMut) {
+        let buf = match self {
+            Writer::ArrowStream(w) => w.get_mut(),
+            Writer::Parquet(w) => w.inner_mut(),
+        };
+        out.extend_from_slice(buf);
+        buf.clear();
+    }
+}
+
+/// Encodes rows as an Arrow IPC stream or a Parquet file, one record batch at a time.
+pub struct ColumnarEncoder {
+    format: ColumnarFormat,
+    batch: Option<BatchBuilder>,
+    writer: Option<Writer>,
+}
+
+impl ColumnarEncoder {
+    pub fn new(format: ColumnarFormat) -> Self {
+        Self {
+            format,
+            batch: None,
+            writer: None,
+        }
+    }
+
+    fn write_batch(&mut self, out: &mut BytesMut) -> Result<()> {
+        let (Some(batch), Some(writer)) = (&mut self.batch, &mut self.writer) else {
+            return Err(anyhow!("Encoder used before begin"));
+        };
+        if batch.rows > 0 {
+            writer.write(&batch.finish()?)?;
+        }
+        writer.drain_into(out);
+        Ok(())
+    }
+}
+
+impl RowEncoder for ColumnarEncoder {
+    fn content_type(&self) -> &'static str {
+        match self.format {
+            ColumnarFormat::ArrowStream => ARROW_STREAM_CONTENT_TYPE,
+            ColumnarFormat::Parquet => PARQUET_CONTENT_TYPE,
+        }
+    }
+
+    fn begin(&mut self, columns: &[Column], out: &mut BytesMut) -> Result<()> {
+        let schema = Arc::new(schema_for(columns));
+        let mut writer = match self.format {
+            ColumnarFormat::ArrowStream => {
+                Writer::ArrowStream(StreamWriter::try_new(Vec::new(), &schema)?)
+            }
+            ColumnarFormat::Parquet => {
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .set_max_row_group_size(PARQUET_ROW_GROUP_SIZE)
+                    .build();
+                Writer::Parquet(ArrowWriter::try_new(
+                    Vec::new(),
+                    schema.clone(),
+                    Some(props),
+                )?)
+            }
+        };
+        writer.drain_into(out);
+
+        self.batch = Some(BatchBuilder::new(schema)?);
+        self.writer = Some(writer);
+        Ok(())
+    }
+
+    fn encode_row(&mut self, row: &Row, out: &mut BytesMut) -> Result<()> {
+        let batch = self
+            .batch
+            .as_mut()
+            .ok_or_else(|| anyhow!("Encoder used before begin"))?;
+        batch.append(row);
+        if batch.rows >= BATCH_SIZE {
+            self.write_batch(out)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut BytesMut) -> Result<()> {
+        self.write_batch(out)?;
+        if let Some(writer) = &mut self.writer {
+            writer.finish()?;
+            writer.drain_into(out);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numeric_precision_scale() {
+        // numeric(10, 2)
+        assert_eq!(numeric_precision_scale((10 << 16) + 2 + 4), Some((10, 2)));
+        assert_eq!(numeric_precision_scale(-1), None);
+        // numeric(50, 0) does not fit in a Decimal128
+        assert_eq!(numeric_precision_scale((50 << 16) + 4), None);
+    }
+
+    #[test]
+    fn test_parse_decimal() {
+        assert_eq!(parse_decimal("123.45", 2), Some(12345));
+        assert_eq!(parse_decimal("-0.5", 3), Some(-500));
+        assert_eq!(parse_decimal("42", 2), Some(4200));
+        assert_eq!(parse_decimal("1.234", 2), None);
+        assert_eq!(parse_decimal("NaN", 2), None);
+    }
+}
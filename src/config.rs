@@ -1,16 +1,28 @@
 use anyhow::Result;
 use serde::Deserialize;
 
+use crate::policy::StatementClass;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub server: ServerConfig,
     pub database: DatabaseConfig,
     pub oidc: OidcConfig,
+    pub role_mapping: Option<RoleMappingConfig>,
+    pub transactions: Option<TransactionConfig>,
+    pub sql_policy: Option<SqlPolicyConfig>,
+    pub queries: Option<Vec<NamedQuery>>,
+    pub rpc: Option<RpcConfig>,
+    pub rest: Option<RestConfig>,
+    pub graphql: Option<GraphqlConfig>,
+    pub listen: Option<ListenConfig>,
+    pub openapi: Option<OpenapiConfig>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ServerConfig {
     pub bind_address: String,
+    pub raw_sql: Option<bool>, // Set to false to only serve named queries, see Config::locked_down
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -21,6 +33,24 @@ pub struct DatabaseConfig {
     pub password: String,
     pub database: String,
     pub max_connections: u32,
+    pub min_idle: Option<u32>,
+    pub max_lifetime_seconds: Option<u64>,
+    pub idle_timeout_seconds: Option<u64>,
+    pub sslmode: Option<SslMode>,
+    pub ssl_root_cert: Option<String>, // CA bundle (PEM) used to verify the server
+    pub ssl_cert: Option<String>,      // Client certificate (PEM)
+    pub ssl_key: Option<String>,       // Client private key (PEM)
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    Disable,
+    #[default]
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -28,18 +58,139 @@ pub struct OidcConfig {
     pub issuer_url: String,
     pub client_id: String,
     pub audience: Option<String>,
+    pub jwks_uri: Option<String>, // Overrides the jwks_uri from OIDC discovery
     pub jwks_cache_duration_seconds: u64,
+    pub allowed_algorithms: Option<Vec<String>>, // e.g. ["ES256", "RS256"]
     pub skip_validation: Option<bool>, // 開発環境用
     pub dev_secret: Option<String>, // 開発環境用のHS256秘密鍵
 }
 
+/// Maps token claims to the PostgreSQL role each request runs as.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RoleMappingConfig {
+    pub rules: Vec<RoleRule>,
+    pub default_role: Option<String>, // Used when no rule matches; requests are rejected if unset
+}
+
+/// Selects `role` when `claim` equals `value`, or contains it if the claim is an array.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RoleRule {
+    pub claim: String, // Claim name, dotted for nested claims (e.g. "realm_access.roles")
+    pub value: String,
+    pub role: String,
+}
+
+/// Settings for interactive transactions started with `POST /transactions`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TransactionConfig {
+    pub idle_timeout_seconds: Option<u64>, // Abandoned transactions are rolled back after this
+    pub max_per_user: Option<usize>, // Open transactions per token subject, defaults to 5
+}
+
+/// Rules restricting the SQL run for users: raw SQL sent to `/query`, `/execute` and
+/// `/batch`, and the SQL generated for `/rpc`, `/tables` and `/graphql`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SqlPolicyConfig {
+    pub rules: Vec<PolicyRule>,
+}
+
+/// Applies to requests running as `role` and/or whose `claim` matches `value`; a rule
+/// with neither applies to every request. All applicable rules must pass.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PolicyRule {
+    pub name: String, // Reported in the error when the rule blocks a request
+    pub role: Option<String>,
+    pub claim: Option<String>,
+    pub value: Option<String>,
+    pub allow: Option<Vec<StatementClass>>, // Only these statement classes may run
+    pub deny: Option<Vec<StatementClass>>,
+    pub schemas: Option<Vec<String>>, // Tables and qualified functions must be in one of these
+}
+
+/// A stored, parameterized query that clients run with `POST /queries/{name}`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct NamedQuery {
+    pub name: String,
+    pub sql: String, // Arguments are bound to $1..$n in the order of `params`
+    pub params: Option<Vec<QueryParam>>,
+    pub role: Option<String>, // Mapped role required to run the query
+    pub claim: Option<String>, // Claim required to run the query, with `value`
+    pub value: Option<String>,
+    pub read_only: Option<bool>, // Run in a read-only transaction (default true)
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct QueryParam {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub param_type: ParamType,
+    pub required: Option<bool>, // Defaults to true unless `default` is set
+    pub default: Option<serde_json::Value>,
+}
+
+/// JSON type a named query argument must have.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    Array,
+    Object,
+}
+
+/// Settings for `POST /rpc/{function}`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RpcConfig {
+    pub enabled: Option<bool>, // Defaults to false
+    pub schemas: Option<Vec<String>>, // Schemas whose routines can be called, defaults to [public]
+}
+
+/// Settings for the generated `/tables/{schema}/{table}` endpoints.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RestConfig {
+    pub enabled: Option<bool>, // Defaults to false
+    pub schemas: Option<Vec<String>>, // Schemas to expose; all non-system schemas if unset
+}
+
+/// Settings for the generated `/graphql` endpoint, which exposes the same schemas as
+/// the table endpoints.
+#[derive(Debug, Deserialize, Clone)]
+pub struct GraphqlConfig {
+    pub enabled: Option<bool>, // Defaults to false
+    pub max_depth: Option<usize>, // Maximum query nesting depth, defaults to 15
+}
+
+/// Settings for `GET /listen/{channel}`; only the channels listed here can be listened to.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ListenConfig {
+    pub channels: Vec<ChannelRule>,
+    pub max_connections: Option<usize>, // Listener connections outside the pool, defaults to 10
+}
+
+/// Allows listening on `channel` to callers whose `claim` matches `value`, or to every
+/// caller if neither is set.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChannelRule {
+    pub channel: String,
+    pub claim: Option<String>,
+    pub value: Option<String>,
+}
+
+/// Settings for `GET /openapi.json`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct OpenapiConfig {
+    pub public: Option<bool>, // Serve without authentication, defaults to false
+}
+
 impl Config {
     pub fn load() -> Result<Self> {
         println!("Loading configuration...");
         eprintln!("Loading configuration...");
         
         // Load .env file if it exists
-        if let Err(_) = dotenvy::dotenv() {
+        if dotenvy::dotenv().is_err() {
             println!("No .env file found, using only environment variables");
         } else {
             println!(".env file loaded successfully");
@@ -66,6 +217,13 @@ impl Config {
         println!("Configuration loaded successfully");
         Ok(config)
     }
+
+    /// Whether `server.raw_sql` is false. A locked-down proxy only runs the SQL of named
+    /// queries, so every endpoint that builds SQL from the request is left out: `/query`,
+    /// `/execute`, `/batch`, `/transactions`, `/rpc`, `/tables` and `/graphql`.
+    pub fn locked_down(&self) -> bool {
+        !self.server.raw_sql.unwrap_or(true)
+    }
 }
 
 impl Default for Config {
@@ -73,6 +231,7 @@ impl Default for Config {
         Self {
             server: ServerConfig {
                 bind_address: "0.0.0.0:8080".to_string(),
+                raw_sql: None,
             },
             database: DatabaseConfig {
                 host: "localhost".to_string(),
@@ -81,15 +240,33 @@ impl Default for Config {
                 password: "password".to_string(),
                 database: "postgres".to_string(),
                 max_connections: 10,
+                min_idle: None,
+                max_lifetime_seconds: None,
+                idle_timeout_seconds: None,
+                sslmode: None,
+                ssl_root_cert: None,
+                ssl_cert: None,
+                ssl_key: None,
             },
             oidc: OidcConfig {
                 issuer_url: "https://your-oidc-provider.com".to_string(),
                 client_id: "your-client-id".to_string(),
                 audience: None,
+                jwks_uri: None,
                 jwks_cache_duration_seconds: 3600,
-                skip_validation: Some(false),
+                allowed_algorithms: None,
+                skip_validation: None,
                 dev_secret: None,
             },
+            role_mapping: None,
+            transactions: None,
+            sql_policy: None,
+            queries: None,
+            rpc: None,
+            rest: None,
+            graphql: None,
+            listen: None,
+            openapi: None,
         }
     }
 }
@@ -109,12 +286,25 @@ mod tests {
         assert_eq!(config.database.password, "password");
         assert_eq!(config.database.database, "postgres");
         assert_eq!(config.database.max_connections, 10);
+        assert_eq!(config.database.min_idle, None);
+        assert_eq!(config.database.sslmode, None);
         assert_eq!(config.oidc.issuer_url, "https://your-oidc-provider.com");
         assert_eq!(config.oidc.client_id, "your-client-id");
         assert_eq!(config.oidc.audience, None);
+        assert_eq!(config.oidc.jwks_uri, None);
         assert_eq!(config.oidc.jwks_cache_duration_seconds, 3600);
         assert_eq!(config.oidc.skip_validation, None);
         assert_eq!(config.oidc.dev_secret, None);
+        assert!(config.role_mapping.is_none());
+        assert!(config.transactions.is_none());
+        assert!(config.sql_policy.is_none());
+        assert!(config.queries.is_none());
+        assert!(config.rpc.is_none());
+        assert!(config.rest.is_none());
+        assert!(config.graphql.is_none());
+        assert!(config.listen.is_none());
+        assert!(config.openapi.is_none());
+        assert_eq!(config.server.raw_sql, None);
     }
 
     #[test]
@@ -122,6 +312,7 @@ mod tests {
         let config = Config {
             server: ServerConfig {
                 bind_address: "0.0.0.0:8080".to_string(),
+                raw_sql: None,
             },
             database: DatabaseConfig {
                 host: "localhost".to_string(),
@@ -130,24 +321,116 @@ mod tests {
                 password: "password".to_string(),
                 database: "postgres".to_string(),
                 max_connections: 10,
+                min_idle: Some(2),
+                max_lifetime_seconds: Some(1800),
+                idle_timeout_seconds: Some(600),
+                sslmode: Some(SslMode::VerifyFull),
+                ssl_root_cert: Some("/etc/ssl/certs/db-ca.pem".to_string()),
+                ssl_cert: None,
+                ssl_key: None,
             },
             oidc: OidcConfig {
                 issuer_url: "https://test.auth0.com".to_string(),
                 client_id: "test-client".to_string(),
                 audience: Some("test-audience".to_string()),
+                jwks_uri: Some("https://test.auth0.com/.well-known/jwks.json".to_string()),
                 jwks_cache_duration_seconds: 3600,
+                allowed_algorithms: Some(vec!["ES256".to_string()]),
                 skip_validation: Some(true),
                 dev_secret: Some("test_dev_secret".to_string()),
             },
+            role_mapping: Some(RoleMappingConfig {
+                rules: vec![RoleRule {
+                    claim: "groups".to_string(),
+                    value: "analysts".to_string(),
+                    role: "analyst".to_string(),
+                }],
+                default_role: None,
+            }),
+            transactions: Some(TransactionConfig {
+                idle_timeout_seconds: Some(30),
+                max_per_user: Some(2),
+            }),
+            sql_policy: Some(SqlPolicyConfig {
+                rules: vec![PolicyRule {
+                    name: "analysts-reporting-only".to_string(),
+                    role: Some("analyst".to_string()),
+                    claim: None,
+                    value: None,
+                    allow: Some(vec![StatementClass::Select]),
+                    deny: None,
+                    schemas: Some(vec!["reporting".to_string()]),
+                }],
+            }),
+            queries: Some(vec![NamedQuery {
+                name: "user_by_email".to_string(),
+                sql: "SELECT * FROM users WHERE email = $1".to_string(),
+                params: Some(vec![QueryParam {
+                    name: "email".to_string(),
+                    param_type: ParamType::String,
+                    required: None,
+                    default: None,
+                }]),
+                role: Some("analyst".to_string()),
+                claim: None,
+                value: None,
+                read_only: None,
+            }]),
+            rpc: Some(RpcConfig {
+                enabled: Some(true),
+                schemas: Some(vec!["api".to_string()]),
+            }),
+            rest: Some(RestConfig {
+                enabled: Some(true),
+                schemas: Some(vec!["public".to_string()]),
+            }),
+            graphql: Some(GraphqlConfig {
+                enabled: Some(true),
+                max_depth: Some(5),
+            }),
+            listen: Some(ListenConfig {
+                channels: vec![ChannelRule {
+                    channel: "order_updates".to_string(),
+                    claim: Some("groups".to_string()),
+                    value: Some("dashboards".to_string()),
+                }],
+                max_connections: Some(5),
+            }),
+            openapi: Some(OpenapiConfig { public: Some(true) }),
         };
 
         assert_eq!(config.server.bind_address, "0.0.0.0:8080");
         assert_eq!(config.database.host, "localhost");
         assert_eq!(config.database.port, 5432);
+        assert_eq!(config.database.min_idle, Some(2));
+        assert_eq!(config.database.max_lifetime_seconds, Some(1800));
+        assert_eq!(config.database.sslmode, Some(SslMode::VerifyFull));
         assert_eq!(config.oidc.issuer_url, "https://test.auth0.com");
         assert_eq!(config.oidc.client_id, "test-client");
         assert_eq!(config.oidc.audience, Some("test-audience".to_string()));
+        assert_eq!(
+            config.oidc.jwks_uri,
+            Some("https://test.auth0.com/.well-known/jwks.json".to_string())
+        );
         assert_eq!(config.oidc.skip_validation, Some(true));
         assert_eq!(config.oidc.dev_secret, Some("test_dev_secret".to_string()));
+        let role_mapping = config.role_mapping.unwrap();
+        assert_eq!(role_mapping.rules[0].role, "analyst");
+        assert_eq!(role_mapping.default_role, None);
+        let transactions = config.transactions.unwrap();
+        assert_eq!(transactions.idle_timeout_seconds, Some(30));
+        assert_eq!(transactions.max_per_user, Some(2));
+        let sql_policy = config.sql_policy.unwrap();
+        assert_eq!(sql_policy.rules[0].allow, Some(vec![StatementClass::Select]));
+        let queries = config.queries.unwrap();
+        assert_eq!(queries[0].name, "user_by_email");
+        assert_eq!(queries[0].params.as_ref().unwrap()[0].param_type, ParamType::String);
+        assert_eq!(config.rpc.unwrap().schemas, Some(vec!["api".to_string()]));
+        assert_eq!(config.rest.unwrap().schemas, Some(vec!["public".to_string()]));
+        assert_eq!(config.graphql.unwrap().max_depth, Some(5));
+        let listen = config.listen.unwrap();
+        assert_eq!(listen.channels[0].channel, "order_updates");
+        assert_eq!(listen.max_connections, Some(5));
+        assert_eq!(config.openapi.unwrap().public, Some(true));
     }
 }
@@ -0,0 +1,289 @@
+use anyhow::{bail, Result};
+use axum::http::{header::ACCEPT, HeaderMap};
+use bytes::{BufMut, BytesMut};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio_postgres::{Column, Row};
+use utoipa::ToSchema;
+
+use crate::columnar::{ColumnarEncoder, ColumnarFormat};
+use crate::types;
+
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+pub const CSV_CONTENT_TYPE: &str = "text/csv; charset=utf-8";
+pub const TSV_CONTENT_TYPE: &str = "text/tab-separated-values; charset=utf-8";
+
+/// How `/query` returns its result rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    /// A single JSON document, built after all rows have been read
+    Json,
+    /// One JSON object per line, streamed as rows arrive
+    Ndjson,
+    /// Comma-separated values, streamed as rows arrive
+    Csv,
+    /// Tab-separated values, streamed as rows arrive
+    Tsv,
+    /// Apache Arrow IPC stream, sent one record batch at a time
+    Arrow,
+    /// Apache Parquet file
+    Parquet,
+}
+
+/// Options for CSV and TSV output, given as `csv` in the query request.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct CsvOptions {
+    /// Whether the first line lists the column names
+    pub header: bool,
+    /// Field delimiter; defaults to `,` for CSV and a tab for TSV
+    pub delimiter: Option<char>,
+    /// How NULL is written; an empty string that is not NULL is written as `""`
+    pub null: String,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            header: true,
+            delimiter: None,
+            null: String::new(),
+        }
+    }
+}
+
+impl ResponseFormat {
+    /// Picks the response format: an explicit `format` in the request wins, then the
+    /// first supported media type in the `Accept` header, then JSON.
+    pub fn negotiate(requested: Option<ResponseFormat>, headers: &HeaderMap) -> Self {
+        if let Some(format) = requested {
+            return format;
+        }
+
+        headers
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .split(',')
+            // Parameters such as `q` are ignored; the client's order decides
+            .filter_map(|range| range.split(';').next())
+            .find_map(|media_type| Self::from_media_type(media_type.trim()))
+            .unwrap_or(ResponseFormat::Json)
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        let media_type = media_type.to_ascii_lowercase();
+        match media_type.as_str() {
+            "application/json" => Some(ResponseFormat::Json),
+            "application/x-ndjson" => Some(ResponseFormat::Ndjson),
+            "text/csv" => Some(ResponseFormat::Csv),
+            "text/tab-separated-values" => Some(ResponseFormat::Tsv),
+            "application/vnd.apache.arrow.stream" => Some(ResponseFormat::Arrow),
+            "application/vnd.apache.parquet" => Some(ResponseFormat::Parquet),
+            _ => None,
+        }
+    }
+
+    /// Returns the encoder for streamed formats, or `None` for the buffered JSON response.
+    pub fn encoder(self, csv: &CsvOptions) -> Result<Option<Box<dyn RowEncoder>>> {
+        Ok(match self {
+            ResponseFormat::Json => None,
+            ResponseFormat::Ndjson => Some(Box::new(NdjsonEncoder)),
+            ResponseFormat::Csv => Some(Box::new(CsvEncoder::new(csv, ',', CSV_CONTENT_TYPE)?)),
+            ResponseFormat::Tsv => Some(Box::new(CsvEncoder::new(csv, '\t', TSV_CONTENT_TYPE)?)),
+            ResponseFormat::Arrow => {
+                Some(Box::new(ColumnarEncoder::new(ColumnarFormat::ArrowStream)))
+            }
+            ResponseFormat::Parquet => {
+                Some(Box::new(ColumnarEncoder::new(ColumnarFormat::Parquet)))
+            }
+        })
+    }
+}
+
+/// Serializes result rows into a streamed response body.
+///
+/// Encoders append to `out`; they may buffer rows internally and write nothing for a
+/// given row, as long as everything is flushed by `finish`.
+pub trait RowEncoder: Send {
+    fn content_type(&self) -> &'static str;
+
+    /// Called once with the result columns before the first row.
+    fn begin(&mut self, _columns: &[Column], _out: &mut BytesMut) -> Result<()> {
+        Ok(())
+    }
+
+    fn encode_row(&mut self, row: &Row, out: &mut BytesMut) -> Result<()>;
+
+    /// Called after the last row.
+    fn finish(&mut self, _out: &mut BytesMut) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Newline-delimited JSON, one object per row keyed by column name.
+struct NdjsonEncoder;
+
+impl RowEncoder for NdjsonEncoder {
+    fn content_type(&self) -> &'static str {
+        NDJSON_CONTENT_TYPE
+    }
+
+    fn encode_row(&mut self, row: &Row, out: &mut BytesMut) -> Result<()> {
+        serde_json::to_writer(out.writer(), &types::row_to_json(row))?;
+        out.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+/// Delimiter-separated values with RFC 4180 quoting and CRLF line endings.
+struct CsvEncoder {
+    header: bool,
+    delimiter: char,
+    null: String,
+    content_type: &'static str,
+}
+
+impl CsvEncoder {
+    fn new(options: &CsvOptions, default_delimiter: char, content_type: &'static str) -> Result<Self> {
+        let delimiter = options.delimiter.unwrap_or(default_delimiter);
+        if matches!(delimiter, '"' | '\r' | '\n') {
+            bail!("Invalid CSV delimiter {:?}", delimiter);
+        }
+        if options.null.contains(delimiter) || options.null.contains(['"', '\r', '\n']) {
+            bail!("CSV null representation must not contain the delimiter, quotes or newlines");
+        }
+
+        Ok(Self {
+            header: options.header,
+            delimiter,
+            null: options.null.clone(),
+            content_type,
+        })
+    }
+
+    fn write_record<'a>(&self, fields: impl Iterator<Item = Option<&'a str>>, out: &mut BytesMut) {
+        let mut delimiter = [0; 4];
+        let delimiter = self.delimiter.encode_utf8(&mut delimiter).as_bytes();
+
+        for (i, field) in fields.enumerate() {
+            if i > 0 {
+                out.put_slice(delimiter);
+            }
+            match field {
+                None => out.put_slice(self.null.as_bytes()),
+                // Quote values that would otherwise be read back differently, including
+                // values that look like the NULL representation
+                Some(value)
+                    if value == self.null
+                        || value.contains(self.delimiter)
+                        || value.contains(['"', '\r', '\n']) =>
+                {
+                    out.put_u8(b'"');
+                    out.put_slice(value.replace('"', "\"\"").as_bytes());
+                    out.put_u8(b'"');
+                }
+                Some(value) => out.put_slice(value.as_bytes()),
+            }
+        }
+        out.put_slice(b"\r\n");
+    }
+}
+
+impl RowEncoder for CsvEncoder {
+    fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+
+    fn begin(&mut self, columns: &[Column], out: &mut BytesMut) -> Result<()> {
+        if self.header {
+            self.write_record(columns.iter().map(|column| Some(column.name())), out);
+        }
+        Ok(())
+    }
+
+    fn encode_row(&mut self, row: &Row, out: &mut BytesMut) -> Result<()> {
+        let values: Vec<Option<String>> = (0..row.len())
+            .map(|i| csv_field(types::column_value(row, i)))
+            .collect();
+        self.write_record(values.iter().map(Option::as_deref), out);
+        Ok(())
+    }
+}
+
+/// Renders a converted column value as CSV text; arrays and JSON values are written as JSON.
+fn csv_field(value: Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s),
+        other => Some(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(accept: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_str(accept).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_negotiate_response_format() {
+        let negotiate = |accept| ResponseFormat::negotiate(None, &headers(accept));
+
+        assert_eq!(ResponseFormat::negotiate(None, &HeaderMap::new()), ResponseFormat::Json);
+        assert_eq!(negotiate("application/json"), ResponseFormat::Json);
+        assert_eq!(negotiate("application/x-ndjson"), ResponseFormat::Ndjson);
+        assert_eq!(negotiate("text/html, Application/X-NDJSON;q=0.9"), ResponseFormat::Ndjson);
+        assert_eq!(negotiate("text/csv, application/json"), ResponseFormat::Csv);
+        assert_eq!(negotiate("text/tab-separated-values"), ResponseFormat::Tsv);
+        assert_eq!(
+            negotiate("application/vnd.apache.arrow.stream"),
+            ResponseFormat::Arrow
+        );
+        assert_eq!(
+            negotiate("application/vnd.apache.parquet"),
+            ResponseFormat::Parquet
+        );
+        assert_eq!(
+            ResponseFormat::negotiate(Some(ResponseFormat::Tsv), &headers("text/csv")),
+            ResponseFormat::Tsv
+        );
+    }
+
+    #[test]
+    fn test_csv_quoting() {
+        let options = CsvOptions {
+            null: "NULL".to_string(),
+            ..Default::default()
+        };
+        let encoder = CsvEncoder::new(&options, ',', CSV_CONTENT_TYPE).unwrap();
+        let mut out = BytesMut::new();
+        encoder.write_record(
+            [Some("plain"), Some("a,b"), Some("say \"hi\""), Some("two\nlines"), None, Some("NULL"), Some("")]
+                .into_iter(),
+            &mut out,
+        );
+        assert_eq!(
+            &out[..],
+            b"plain,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",NULL,\"NULL\",\r\n"
+        );
+
+        let mut out = BytesMut::new();
+        CsvEncoder::new(&CsvOptions::default(), '\t', TSV_CONTENT_TYPE)
+            .unwrap()
+            .write_record([Some("a,b"), Some("c\td"), None, Some("")].into_iter(), &mut out);
+        assert_eq!(&out[..], b"a,b\t\"c\td\"\t\t\"\"\r\n");
+
+        let invalid = CsvOptions {
+            delimiter: Some('"'),
+            ..Default::default()
+        };
+        assert!(CsvEncoder::new(&invalid, ',', CSV_CONTENT_TYPE).is_err());
+    }
+}
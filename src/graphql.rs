@@ -0,0 +1,1121 @@
+use anyhow::{anyhow, bail, Result};
+use async_graphql::dynamic::{
+    Enum, Field, FieldFuture, FieldValue, InputObject, InputValue, Object, ObjectAccessor,
+    ResolverContext, Scalar, Schema, SchemaBuilder, Type, TypeRef, ValueAccessor,
+};
+use async_graphql::parser::types::{DocumentOperations, OperationType};
+use async_graphql::{ErrorExtensions, Request, Response, Value as GraphqlValue};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::catalog::{Catalog, Table, TableKind};
+use crate::config::{Config, RpcConfig};
+use crate::oidc::{AuthenticatedUser, Claims};
+use crate::postgres::PostgresClient;
+use crate::rest::{self, Filter, Operator, ReadQuery};
+use crate::rpc::{self, Returns, Routine};
+use crate::sql::quote_ident;
+use crate::{api_error, db_error, get_client, mapped_role, policy, session, types};
+use crate::{ApiError, AppState};
+
+const BIG_INT: &str = "BigInt";
+const JSON: &str = "JSON";
+const ORDER_BY: &str = "order_by";
+
+/// Sort directions of the `order_by` enum and the SQL they stand for.
+const DIRECTIONS: [(&str, &str); 6] = [
+    ("asc", "ASC"),
+    ("desc", "DESC"),
+    ("asc_nulls_first", "ASC NULLS FIRST"),
+    ("asc_nulls_last", "ASC NULLS LAST"),
+    ("desc_nulls_first", "DESC NULLS FIRST"),
+    ("desc_nulls_last", "DESC NULLS LAST"),
+];
+
+/// The caller and the connection a GraphQL request runs on, available to every resolver.
+struct RequestContext {
+    client: Arc<PostgresClient>,
+    config: Arc<Config>,
+    role: Option<String>,
+    claims: Claims,
+}
+
+/// Runs a GraphQL request in one transaction as the caller's role. Queries run
+/// read-only; mutations are committed only if every field succeeded.
+pub async fn execute(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Json(request): Json<Request>,
+) -> Result<Json<Response>, ApiError> {
+    info!("Executing GraphQL request for user {}", user.sub);
+
+    let schema = state
+        .graphql
+        .clone()
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "GraphQL is not enabled"))?;
+    let role = mapped_role(&state, &user)?;
+
+    let client = get_client(&state).await?;
+    let begin = if is_mutation(&request) {
+        "BEGIN"
+    } else {
+        "BEGIN READ ONLY"
+    };
+    client
+        .batch_execute(begin)
+        .await
+        .map_err(|e| db_error("Failed to start database session", &e))?;
+    if let Err(e) = session::configure(&*client, role.as_deref(), &user).await {
+        // Leave the connection clean so the pool can reuse it
+        let _ = client.batch_execute("ROLLBACK").await;
+        return Err(db_error("Failed to start database session", &e));
+    }
+
+    let client = Arc::new(client);
+    let AuthenticatedUser(claims) = user;
+    let context = RequestContext {
+        client: client.clone(),
+        config: state.config.clone(),
+        role,
+        claims,
+    };
+    let response = schema.execute(request.data(context)).await;
+
+    let end = if response.is_ok() { "COMMIT" } else { "ROLLBACK" };
+    client
+        .batch_execute(end)
+        .await
+        .map_err(|e| db_error("GraphQL transaction failed", &e))?;
+
+    Ok(Json(response))
+}
+
+/// Whether the operation the request runs is a mutation. Requests that do not parse
+/// are treated as queries; executing them reports the error.
+fn is_mutation(request: &Request) -> bool {
+    let Ok(document) = async_graphql::parser::parse_query(&request.query) else {
+        return false;
+    };
+    let operation = match (&document.operations, &request.operation_name) {
+        (DocumentOperations::Single(operation), _) => Some(operation),
+        (DocumentOperations::Multiple(operations), Some(name)) => operations.get(name.as_str()),
+        (DocumentOperations::Multiple(_), None) => None,
+    };
+    operation.is_some_and(|operation| operation.node.ty == OperationType::Mutation)
+}
+
+/// Generates the schema for the catalog's tables, views and functions. Returns `None`
+/// if there is nothing to expose.
+///
+/// Functions are exposed like through `/rpc`: only if `rpc` is given because that
+/// endpoint is enabled, and only those in `rpc.schemas`.
+pub fn build_schema(
+    catalog: &Catalog,
+    rpc: Option<&RpcConfig>,
+    max_depth: usize,
+) -> Result<Option<Schema>> {
+    let tables: Vec<&Table> = catalog.tables().collect();
+    let functions = callable_functions(catalog.functions(), rpc);
+    let Some(builder) = schema_builder(&tables, &functions) else {
+        return Ok(None);
+    };
+    let schema = builder
+        .limit_depth(max_depth)
+        .finish()
+        .map_err(|e| anyhow!("Invalid GraphQL schema: {}", e.0))?;
+    Ok(Some(schema))
+}
+
+/// A table as exposed in the schema.
+struct TableType {
+    table: Arc<Table>,
+    /// Name of the object type and of the query field listing its rows
+    name: String,
+    /// Columns whose names are valid GraphQL names
+    columns: Vec<usize>,
+    /// Whether any of those columns can be filtered and sorted on
+    comparable: bool,
+    relationships: Vec<Relationship>,
+}
+
+/// A field following a foreign key from one table to another, in either direction.
+struct Relationship {
+    field: String,
+    /// Index of the related table in the exposed tables
+    target: usize,
+    /// Whether the related table holds the foreign key, so there can be many rows
+    many: bool,
+    /// Columns of this table and the matching columns of the related table
+    columns: Vec<String>,
+    target_columns: Vec<String>,
+}
+
+fn schema_builder(tables: &[&Table], functions: &[Routine]) -> Option<SchemaBuilder> {
+    let mut used_names = HashSet::new();
+    let mut exposed: Vec<TableType> = Vec::new();
+    for table in tables {
+        let name = type_name(&table.schema, &table.name);
+        if !is_valid_name(&name) || !reserve_names(&mut used_names, &table_names(&name)) {
+            warn!(
+                "Table {}.{} cannot be exposed through GraphQL as '{}'",
+                table.schema, table.name, name
+            );
+            continue;
+        }
+        let columns: Vec<usize> = table
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(_, column)| {
+                let valid = is_valid_name(&column.name);
+                if !valid {
+                    warn!(
+                        "Column {}.{}.{} has no valid GraphQL name and is not exposed",
+                        table.schema, table.name, column.name
+                    );
+                }
+                valid
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if columns.is_empty() {
+            continue;
+        }
+        let comparable = columns
+            .iter()
+            .any(|&i| comparable_type(&table.columns[i].type_name).is_some());
+        exposed.push(TableType {
+            table: Arc::new((*table).clone()),
+            name,
+            columns,
+            comparable,
+            relationships: Vec::new(),
+        });
+    }
+    add_relationships(&mut exposed);
+
+    let mut types: Vec<Type> = Vec::new();
+    let mut query = Object::new("Query");
+    let mut mutation = Object::new("Mutation");
+    let mut has_queries = !exposed.is_empty();
+    let mut has_mutations = false;
+    for table_type in &exposed {
+        let (object, inputs) = table_types(table_type, &exposed);
+        types.push(object.into());
+        types.extend(inputs.into_iter().map(Type::from));
+        for field in query_fields(table_type) {
+            query = query.field(field);
+        }
+        for field in mutation_fields(table_type) {
+            mutation = mutation.field(field);
+            has_mutations = true;
+        }
+    }
+    for function in exposed_functions(functions, &mut used_names) {
+        if function.is_procedure || function.is_volatile {
+            mutation = mutation.field(function_field(function));
+            has_mutations = true;
+        } else {
+            query = query.field(function_field(function));
+            has_queries = true;
+        }
+    }
+    if !has_queries {
+        return None;
+    }
+
+    // The mutation root type must have fields, so it is left out if there are none
+    let mut builder = Schema::build("Query", has_mutations.then_some("Mutation"), None)
+        .register(query)
+        .register(Scalar::new(BIG_INT).description("A 64-bit integer"))
+        .register(Scalar::new(JSON).description("Any JSON value"))
+        .register(Enum::new(ORDER_BY).items(DIRECTIONS.iter().map(|(name, _)| *name)));
+    if has_mutations {
+        builder = builder.register(mutation);
+    }
+    for scalar in [TypeRef::INT, BIG_INT, TypeRef::FLOAT, TypeRef::STRING, TypeRef::BOOLEAN] {
+        builder = builder.register(comparison_type(scalar));
+    }
+    for ty in types {
+        builder = builder.register(ty);
+    }
+    Some(builder)
+}
+
+/// Names the schema gives a table: the object type, its input types and its root
+/// fields. A table is only exposed if none of them collide with another table's.
+fn table_names(name: &str) -> Vec<String> {
+    vec![
+        name.to_string(),
+        format!("{}_by_pk", name),
+        format!("{}_filter", name),
+        format!("{}_order_by", name),
+        format!("{}_input", name),
+        format!("insert_{}", name),
+        format!("update_{}_by_pk", name),
+        format!("delete_{}_by_pk", name),
+    ]
+}
+
+fn reserve_names(used_names: &mut HashSet<String>, names: &[String]) -> bool {
+    if names.iter().any(|name| used_names.contains(name)) {
+        return false;
+    }
+    used_names.extend(names.iter().cloned());
+    true
+}
+
+/// Adds a field for each foreign key to the referencing table, and a list field for
+/// the referencing rows to the referenced table. Fields are named after the related
+/// table, or after the constraint if that name is taken.
+fn add_relationships(exposed: &mut [TableType]) {
+    let index: HashMap<(String, String), usize> = exposed
+        .iter()
+        .enumerate()
+        .map(|(i, t)| ((t.table.schema.clone(), t.table.name.clone()), i))
+        .collect();
+    let mut used: Vec<HashSet<String>> = exposed
+        .iter()
+        .map(|t| {
+            t.columns
+                .iter()
+                .map(|&i| t.table.columns[i].name.clone())
+                .collect()
+        })
+        .collect();
+
+    let mut field_name = |table: usize, preferred: String, fallback: String| {
+        [preferred, fallback]
+            .into_iter()
+            .find(|name| is_valid_name(name) && used[table].insert(name.clone()))
+    };
+
+    let mut relationships = Vec::new();
+    for (source, table_type) in exposed.iter().enumerate() {
+        for foreign_key in &table_type.table.foreign_keys {
+            let key = (
+                foreign_key.referenced_schema.clone(),
+                foreign_key.referenced_table.clone(),
+            );
+            let Some(&target) = index.get(&key) else {
+                continue;
+            };
+
+            let forward = field_name(
+                source,
+                exposed[target].name.clone(),
+                foreign_key.name.clone(),
+            );
+            let reverse = field_name(
+                target,
+                table_type.name.clone(),
+                format!("{}_by_{}", table_type.name, foreign_key.name),
+            );
+            if forward.is_none() || reverse.is_none() {
+                warn!(
+                    "Foreign key {} of {}.{} has no free GraphQL field name",
+                    foreign_key.name, table_type.table.schema, table_type.table.name
+                );
+            }
+            if let Some(field) = forward {
+                relationships.push((
+                    source,
+                    Relationship {
+                        field,
+                        target,
+                        many: false,
+                        columns: foreign_key.columns.clone(),
+                        target_columns: foreign_key.referenced_columns.clone(),
+                    },
+                ));
+            }
+            if let Some(field) = reverse {
+                relationships.push((
+                    target,
+                    Relationship {
+                        field,
+                        target: source,
+                        many: true,
+                        columns: foreign_key.referenced_columns.clone(),
+                        target_columns: foreign_key.columns.clone(),
+                    },
+                ));
+            }
+        }
+    }
+    for (table, relationship) in relationships {
+        exposed[table].relationships.push(relationship);
+    }
+}
+
+/// The object type of a table's rows, and its filter, order and input types.
+fn table_types(table_type: &TableType, exposed: &[TableType]) -> (Object, Vec<InputObject>) {
+    let table = &table_type.table;
+    let name = &table_type.name;
+    let mut object = Object::new(name);
+    let mut filter = InputObject::new(format!("{}_filter", name));
+    let mut order_by = InputObject::new(format!("{}_order_by", name));
+    let mut input = InputObject::new(format!("{}_input", name));
+
+    for column in table_type.columns.iter().map(|&i| &table.columns[i]) {
+        let column_name = column.name.clone();
+        object = object.field(Field::new(
+            &column.name,
+            value_type(&column.type_name, !column.nullable),
+            move |ctx| {
+                let column_name = column_name.clone();
+                FieldFuture::new(async move {
+                    let row = ctx.parent_value.try_downcast_ref::<Map<String, Value>>()?;
+                    Ok(row
+                        .get(&column_name)
+                        .filter(|value| !value.is_null())
+                        .map(|value| FieldValue::value(graphql_value(value.clone()))))
+                })
+            },
+        ));
+
+        if let Some(scalar) = comparable_type(&column.type_name) {
+            filter = filter.field(InputValue::new(
+                &column.name,
+                TypeRef::named(format!("{}_comparison", scalar)),
+            ));
+            order_by = order_by.field(InputValue::new(&column.name, TypeRef::named(ORDER_BY)));
+        }
+        input = input.field(InputValue::new(
+            &column.name,
+            value_type(&column.type_name, false),
+        ));
+    }
+
+    for relationship in &table_type.relationships {
+        object = object.field(relationship_field(table_type, relationship, exposed));
+    }
+
+    let mut inputs = Vec::new();
+    if table_type.comparable {
+        inputs.extend([filter, order_by]);
+    }
+    if table.kind != TableKind::MaterializedView {
+        inputs.push(input);
+    }
+    (object, inputs)
+}
+
+fn relationship_field(
+    table_type: &TableType,
+    relationship: &Relationship,
+    exposed: &[TableType],
+) -> Field {
+    let target = &exposed[relationship.target];
+    let target_table = target.table.clone();
+    let columns = relationship.columns.clone();
+    let target_columns = relationship.target_columns.clone();
+    let many = relationship.many;
+    let field_type = if many {
+        TypeRef::named_nn_list_nn(&target.name)
+    } else {
+        TypeRef::named(&target.name)
+    };
+
+    let field = Field::new(&relationship.field, field_type, move |ctx| {
+        let table = target_table.clone();
+        let columns = columns.clone();
+        let target_columns = target_columns.clone();
+        FieldFuture::new(async move {
+            let row = ctx.parent_value.try_downcast_ref::<Map<String, Value>>()?;
+            // A row whose key columns are null has no related rows
+            let Some(key_filters) = key_filters(row, &columns, &target_columns) else {
+                if many {
+                    return Ok(Some(FieldValue::list(Vec::<FieldValue>::new())));
+                }
+                return Ok(None);
+            };
+            let mut query = if many {
+                read_query(&ctx.args)?
+            } else {
+                ReadQuery::default()
+            };
+            query.filters.extend(key_filters);
+
+            let (sql, params) = rest::select_sql(&table, &query);
+            let rows = query_rows(&ctx, &sql, &params).await?;
+            if many {
+                Ok(Some(FieldValue::list(rows.into_iter().map(FieldValue::owned_any))))
+            } else {
+                Ok(rows.into_iter().next().map(FieldValue::owned_any))
+            }
+        })
+    })
+    .description(format!(
+        "Rows of {}.{} related to this {} row",
+        target.table.schema, target.table.name, table_type.name
+    ));
+
+    if many {
+        with_read_arguments(field, target)
+    } else {
+        field
+    }
+}
+
+/// `<table>` listing rows, and `<table>_by_pk` fetching one row by primary key.
+fn query_fields(table_type: &TableType) -> Vec<Field> {
+    let name = &table_type.name;
+    let table = table_type.table.clone();
+    let list = Field::new(name, TypeRef::named_nn_list_nn(name), move |ctx| {
+        let table = table.clone();
+        FieldFuture::new(async move {
+            let query = read_query(&ctx.args)?;
+            let (sql, params) = rest::select_sql(&table, &query);
+            let rows = query_rows(&ctx, &sql, &params).await?;
+            Ok(Some(FieldValue::list(rows.into_iter().map(FieldValue::owned_any))))
+        })
+    })
+    .description(format!(
+        "Rows of {}.{}",
+        table_type.table.schema, table_type.table.name
+    ));
+    let mut fields = vec![with_read_arguments(list, table_type)];
+
+    if let Some(primary_key) = primary_key_arguments(table_type) {
+        let table = table_type.table.clone();
+        let by_pk = Field::new(format!("{}_by_pk", name), TypeRef::named(name), move |ctx| {
+            let table = table.clone();
+            FieldFuture::new(async move {
+                let query = ReadQuery {
+                    filters: primary_key_filters(&table, &ctx.args)?,
+                    ..Default::default()
+                };
+                let (sql, params) = rest::select_sql(&table, &query);
+                let rows = query_rows(&ctx, &sql, &params).await?;
+                Ok(rows.into_iter().next().map(FieldValue::owned_any))
+            })
+        });
+        fields.push(with_arguments(by_pk, &primary_key));
+    }
+    fields
+}
+
+/// `insert_<table>`, and for tables with a primary key `update_<table>_by_pk` and
+/// `delete_<table>_by_pk`. Materialized views cannot be modified.
+fn mutation_fields(table_type: &TableType) -> Vec<Field> {
+    if table_type.table.kind == TableKind::MaterializedView {
+        return Vec::new();
+    }
+    let name = &table_type.name;
+    let input = format!("{}_input", name);
+
+    let table = table_type.table.clone();
+    let insert = Field::new(
+        format!("insert_{}", name),
+        TypeRef::named_nn_list_nn(name),
+        move |ctx| {
+            let table = table.clone();
+            FieldFuture::new(async move {
+                let rows = ctx
+                    .args
+                    .try_get("objects")?
+                    .list()?
+                    .iter()
+                    .map(|object| input_object(&object))
+                    .collect::<async_graphql::Result<Vec<_>>>()?;
+                let (sql, params) = rest::insert_sql(&table, &rows).map_err(graphql_error)?;
+                let rows = query_rows(&ctx, &sql, &params).await?;
+                Ok(Some(FieldValue::list(rows.into_iter().map(FieldValue::owned_any))))
+            })
+        },
+    )
+    .argument(InputValue::new(
+        "objects",
+        TypeRef::named_nn_list_nn(&input),
+    ));
+    let mut fields = vec![insert];
+
+    let Some(primary_key) = primary_key_arguments(table_type) else {
+        return fields;
+    };
+
+    let table = table_type.table.clone();
+    let update = Field::new(
+        format!("update_{}_by_pk", name),
+        TypeRef::named(name),
+        move |ctx| {
+            let table = table.clone();
+            FieldFuture::new(async move {
+                let filters = primary_key_filters(&table, &ctx.args)?;
+                let values = input_object(&ctx.args.try_get("set")?)?;
+                let (sql, params) =
+                    rest::update_sql(&table, &values, &filters).map_err(graphql_error)?;
+                let rows = query_rows(&ctx, &sql, &params).await?;
+                Ok(rows.into_iter().next().map(FieldValue::owned_any))
+            })
+        },
+    );
+
+    let table = table_type.table.clone();
+    let delete = Field::new(
+        format!("delete_{}_by_pk", name),
+        TypeRef::named(name),
+        move |ctx| {
+            let table = table.clone();
+            FieldFuture::new(async move {
+                let filters = primary_key_filters(&table, &ctx.args)?;
+                let (sql, params) = rest::delete_sql(&table, &filters);
+                let rows = query_rows(&ctx, &sql, &params).await?;
+                Ok(rows.into_iter().next().map(FieldValue::owned_any))
+            })
+        },
+    );
+
+    fields.push(
+        with_arguments(update, &primary_key)
+            .argument(InputValue::new("set", TypeRef::named_nn(&input))),
+    );
+    fields.push(with_arguments(delete, &primary_key));
+    fields
+}
+
+/// Names and types of the arguments selecting a row by primary key, or `None` if the
+/// table has none or one of its columns is not exposed.
+fn primary_key_arguments(table_type: &TableType) -> Option<Vec<(String, TypeRef)>> {
+    let table = &table_type.table;
+    if table.primary_key.is_empty() {
+        return None;
+    }
+    table
+        .primary_key
+        .iter()
+        .map(|key| {
+            let column = table.column(key).filter(|column| is_valid_name(&column.name))?;
+            Some((column.name.clone(), value_type(&column.type_name, true)))
+        })
+        .collect()
+}
+
+fn with_arguments(field: Field, arguments: &[(String, TypeRef)]) -> Field {
+    arguments.iter().fold(field, |field, (name, ty)| {
+        field.argument(InputValue::new(name, ty.clone()))
+    })
+}
+
+/// Adds `filter`, `order_by`, `limit` and `offset` to a field listing rows.
+fn with_read_arguments(field: Field, table_type: &TableType) -> Field {
+    let name = &table_type.name;
+    let field = if table_type.comparable {
+        field
+            .argument(InputValue::new(
+                "filter",
+                TypeRef::named(format!("{}_filter", name)),
+            ))
+            .argument(InputValue::new(
+                "order_by",
+                TypeRef::named_nn_list(format!("{}_order_by", name)),
+            ))
+    } else {
+        field
+    };
+    field
+        .argument(InputValue::new("limit", TypeRef::named(TypeRef::INT)))
+        .argument(InputValue::new("offset", TypeRef::named(TypeRef::INT)))
+}
+
+/// `<Scalar>_comparison`, the operators available when filtering on a column.
+fn comparison_type(scalar: &str) -> InputObject {
+    let mut comparison = InputObject::new(format!("{}_comparison", scalar));
+    for operator in ["eq", "neq", "gt", "gte", "lt", "lte"] {
+        comparison = comparison.field(InputValue::new(operator, TypeRef::named(scalar)));
+    }
+    if scalar == TypeRef::STRING {
+        for operator in ["like", "ilike"] {
+            comparison = comparison.field(InputValue::new(operator, TypeRef::named(scalar)));
+        }
+    }
+    comparison
+        .field(InputValue::new("in", TypeRef::named_nn_list(scalar)))
+        .field(InputValue::new("is_null", TypeRef::named(TypeRef::BOOLEAN)))
+}
+
+/// The functions `/rpc` could call, or none if it is disabled.
+fn callable_functions(functions: &[Routine], rpc: Option<&RpcConfig>) -> Vec<Routine> {
+    let Some(rpc) = rpc else {
+        return Vec::new();
+    };
+    functions
+        .iter()
+        .filter(|function| rpc::is_exposed(Some(rpc), &function.schema))
+        .cloned()
+        .collect()
+}
+
+/// Functions that can be exposed as fields: one per name, with named inputs. Stable
+/// and immutable functions become queries, the others mutations.
+fn exposed_functions<'a>(
+    functions: &'a [Routine],
+    used_names: &mut HashSet<String>,
+) -> Vec<&'a Routine> {
+    let mut overloads: HashMap<(&str, &str), usize> = HashMap::new();
+    for function in functions {
+        *overloads.entry((&function.schema, &function.name)).or_default() += 1;
+    }
+
+    let mut exposed = Vec::new();
+    let mut reported = HashSet::new();
+    for function in functions {
+        let name = type_name(&function.schema, &function.name);
+        let key = (function.schema.as_str(), function.name.as_str());
+        let skip_reason = if overloads[&key] > 1 {
+            if !reported.insert(key) {
+                continue;
+            }
+            Some("it is overloaded")
+        } else if function
+            .args
+            .iter()
+            .any(|arg| arg.mode.is_input() && !is_valid_name(&arg.name))
+        {
+            Some("not all of its arguments have valid GraphQL names")
+        } else if !is_valid_name(&name) || !reserve_names(used_names, &[name]) {
+            Some("its name is not a valid GraphQL name or is taken")
+        } else {
+            None
+        };
+        match skip_reason {
+            Some(reason) => warn!(
+                "Function {}.{} is not exposed through GraphQL: {}",
+                function.schema, function.name, reason
+            ),
+            None => exposed.push(function),
+        }
+    }
+    exposed
+}
+
+fn function_field(function: &Routine) -> Field {
+    let return_type = match function.returns {
+        _ if function.is_procedure => TypeRef::named(JSON),
+        Returns::Scalar => value_type(&function.return_type, false),
+        Returns::Void => TypeRef::named(TypeRef::BOOLEAN),
+        Returns::Row | Returns::Set => TypeRef::named(JSON),
+    };
+
+    let routine = Arc::new(function.clone());
+    let field = Field::new(
+        type_name(&function.schema, &function.name),
+        return_type,
+        move |ctx| {
+            let routine = routine.clone();
+            FieldFuture::new(async move {
+                let request = ctx.data::<RequestContext>()?;
+                let args = ctx
+                    .args
+                    .iter()
+                    .map(|(name, value)| Ok((name.to_string(), json_value(&value)?)))
+                    .collect::<async_graphql::Result<Map<String, Value>>>()?;
+                let (sql, params) = rpc::build_call(&routine, &args);
+                check_policy(request, &sql)?;
+
+                let statement = types::prepare(&**request.client, &sql)
+                    .await
+                    .map_err(database_error)?;
+                let params = rpc::bind_args(statement.params(), &params).map_err(graphql_error)?;
+                let rows = request
+                    .client
+                    .query(&statement, &types::param_refs(&params))
+                    .await
+                    .map_err(database_error)?;
+                let value = rpc::result_value(&routine, &rows);
+                Ok(Some(FieldValue::value(graphql_value(value))))
+            })
+        },
+    )
+    .description(format!("Calls {}.{}", function.schema, function.name));
+
+    function
+        .args
+        .iter()
+        .filter(|arg| arg.mode.is_input())
+        .fold(field, |field, arg| {
+            field.argument(InputValue::new(
+                &arg.name,
+                value_type(&arg.type_name, !arg.has_default),
+            ))
+        })
+}
+
+/// Runs generated SQL on the request's connection, subject to the caller's SQL policy.
+async fn query_rows(
+    ctx: &ResolverContext<'_>,
+    sql: &str,
+    params: &[Value],
+) -> async_graphql::Result<Vec<Map<String, Value>>> {
+    let request = ctx.data::<RequestContext>()?;
+    check_policy(request, sql)?;
+
+    let statement = types::prepare(&**request.client, sql)
+        .await
+        .map_err(database_error)?;
+    let params = types::bind_params(statement.params(), params).map_err(graphql_error)?;
+    let rows = request
+        .client
+        .query(&statement, &types::param_refs(&params))
+        .await
+        .map_err(database_error)?;
+    Ok(rows.iter().map(types::row_to_json).collect())
+}
+
+/// Checks generated SQL against the caller's SQL policy, as for raw SQL.
+fn check_policy(request: &RequestContext, sql: &str) -> async_graphql::Result<()> {
+    policy::check(
+        request.config.sql_policy.as_ref(),
+        request.role.as_deref(),
+        &request.claims,
+        sql,
+    )
+    .map_err(|e| match e {
+        policy::PolicyError::Blocked { ref rule, .. } => {
+            let rule = rule.clone();
+            graphql_error(e).extend_with(|_, extensions| extensions.set("rule", rule))
+        }
+        e => graphql_error(e),
+    })
+}
+
+/// Converts the `filter`, `order_by`, `limit` and `offset` arguments of a list field.
+fn read_query(args: &ObjectAccessor) -> async_graphql::Result<ReadQuery> {
+    let mut query = ReadQuery::default();
+    if let Some(filter) = args.get("filter").filter(|filter| !filter.is_null()) {
+        query.filters = parse_filter(&filter.object()?).map_err(graphql_error)?;
+    }
+    if let Some(order_by) = args.get("order_by").filter(|order_by| !order_by.is_null()) {
+        for terms in order_by.list()?.iter() {
+            for (column, direction) in terms.object()?.iter() {
+                let direction = direction.enum_name()?;
+                let (_, sql) = DIRECTIONS
+                    .iter()
+                    .find(|(name, _)| *name == direction)
+                    .ok_or_else(|| format!("Unknown sort direction '{}'", direction))?;
+                query.order.push(format!("{} {}", quote_ident(column), sql));
+            }
+        }
+    }
+    query.limit = count_argument(args, "limit")?;
+    query.offset = count_argument(args, "offset")?;
+    Ok(query)
+}
+
+fn count_argument(args: &ObjectAccessor, name: &str) -> async_graphql::Result<Option<u64>> {
+    match args.get(name).filter(|value| !value.is_null()) {
+        Some(value) => {
+            let count = value.i64()?;
+            u64::try_from(count)
+                .map(Some)
+                .map_err(|_| format!("'{}' must not be negative", name).into())
+        }
+        None => Ok(None),
+    }
+}
+
+/// Converts `{column: {operator: value}}` into filters. Columns are exposed under their
+/// own names, and values are passed as text and cast to the column type, as in the
+/// table endpoints.
+fn parse_filter(filter: &ObjectAccessor) -> Result<Vec<Filter>> {
+    let mut filters = Vec::new();
+    for (column, comparison) in filter.iter() {
+        let Ok(comparison) = comparison.object() else {
+            continue;
+        };
+        for (operator, value) in comparison.iter() {
+            if value.is_null() {
+                continue;
+            }
+            let value = value.as_value();
+            let (negated, operator) = match operator.as_str() {
+                "eq" => (false, Operator::Compare("=", text_value(value))),
+                "neq" => (false, Operator::Compare("<>", text_value(value))),
+                "gt" => (false, Operator::Compare(">", text_value(value))),
+                "gte" => (false, Operator::Compare(">=", text_value(value))),
+                "lt" => (false, Operator::Compare("<", text_value(value))),
+                "lte" => (false, Operator::Compare("<=", text_value(value))),
+                "like" => (false, Operator::Like("LIKE", text_value(value))),
+                "ilike" => (false, Operator::Like("ILIKE", text_value(value))),
+                "in" => match value {
+                    GraphqlValue::List(values) => {
+                        (false, Operator::In(values.iter().map(text_value).collect()))
+                    }
+                    value => (false, Operator::In(vec![text_value(value)])),
+                },
+                "is_null" => match value {
+                    GraphqlValue::Boolean(is_null) => (!is_null, Operator::Is("NULL")),
+                    _ => bail!("'is_null' takes a boolean"),
+                },
+                operator => bail!("Unknown filter operator '{}'", operator),
+            };
+            filters.push(Filter {
+                column: column.to_string(),
+                negated,
+                operator,
+            });
+        }
+    }
+    Ok(filters)
+}
+
+fn primary_key_filters(table: &Table, args: &ObjectAccessor) -> async_graphql::Result<Vec<Filter>> {
+    table
+        .primary_key
+        .iter()
+        .map(|key| {
+            let value = args.try_get(key)?;
+            Ok(Filter {
+                column: key.clone(),
+                negated: false,
+                operator: Operator::Compare("=", text_value(value.as_value())),
+            })
+        })
+        .collect()
+}
+
+/// Filters selecting the rows related to `row` through a foreign key, or `None` if any
+/// of its key columns is null.
+fn key_filters(
+    row: &Map<String, Value>,
+    columns: &[String],
+    target_columns: &[String],
+) -> Option<Vec<Filter>> {
+    columns
+        .iter()
+        .zip(target_columns)
+        .map(|(column, target)| {
+            let value = match row.get(column)? {
+                Value::Null => return None,
+                Value::String(s) => s.clone(),
+                value => value.to_string(),
+            };
+            Some(Filter {
+                column: target.clone(),
+                negated: false,
+                operator: Operator::Compare("=", value),
+            })
+        })
+        .collect()
+}
+
+/// Converts an input object to the JSON object of column values it stands for.
+fn input_object(value: &ValueAccessor) -> async_graphql::Result<Map<String, Value>> {
+    match json_value(value)? {
+        Value::Object(object) => Ok(object),
+        _ => Err("Expected an input object".into()),
+    }
+}
+
+fn json_value(value: &ValueAccessor) -> async_graphql::Result<Value> {
+    Ok(value.as_value().clone().into_json()?)
+}
+
+fn graphql_value(value: Value) -> GraphqlValue {
+    GraphqlValue::from_json(value).unwrap_or(GraphqlValue::Null)
+}
+
+/// The text form of a filter value, cast to the column type in SQL.
+fn text_value(value: &GraphqlValue) -> String {
+    match value {
+        GraphqlValue::String(s) => s.clone(),
+        GraphqlValue::Enum(name) => name.to_string(),
+        value => value
+            .clone()
+            .into_json()
+            .map(|value| value.to_string())
+            .unwrap_or_default(),
+    }
+}
+
+fn graphql_error(e: impl ToString) -> async_graphql::Error {
+    async_graphql::Error::new(e.to_string())
+}
+
+/// Converts a database error, keeping its SQLSTATE as the `code` extension.
+fn database_error(e: tokio_postgres::Error) -> async_graphql::Error {
+    warn!("GraphQL statement failed: {}", e);
+    let Some(db) = e.as_db_error() else {
+        return graphql_error(e);
+    };
+    let code = db.code().code().to_string();
+    let detail = db.detail().map(str::to_string);
+    graphql_error(db.message()).extend_with(|_, extensions| {
+        extensions.set("code", code);
+        if let Some(detail) = detail {
+            extensions.set("detail", detail);
+        }
+    })
+}
+
+/// The GraphQL name of a table or function: its own name in `public`, and prefixed
+/// with the schema otherwise.
+fn type_name(schema: &str, name: &str) -> String {
+    if schema == "public" {
+        name.to_string()
+    } else {
+        format!("{}_{}", schema, name)
+    }
+}
+
+/// Checks for `[_A-Za-z][_0-9A-Za-z]*`, excluding the `__` prefix GraphQL reserves.
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        && !name.starts_with("__")
+}
+
+/// The scalar for a PostgreSQL type, as spelled by `format_type`. Types without a
+/// closer match, such as timestamps, numerics and UUIDs, are strings.
+fn scalar_type(type_name: &str) -> &'static str {
+    match type_name {
+        "boolean" => TypeRef::BOOLEAN,
+        "smallint" | "integer" => TypeRef::INT,
+        "bigint" | "oid" => BIG_INT,
+        "real" | "double precision" => TypeRef::FLOAT,
+        "json" | "jsonb" => JSON,
+        _ => TypeRef::STRING,
+    }
+}
+
+/// The type of a column or argument; arrays are lists of their element type.
+fn value_type(type_name: &str, non_null: bool) -> TypeRef {
+    match (type_name.strip_suffix("[]"), non_null) {
+        (Some(element), true) => TypeRef::named_list_nn(scalar_type(element)),
+        (Some(element), false) => TypeRef::named_list(scalar_type(element)),
+        (None, true) => TypeRef::named_nn(scalar_type(type_name)),
+        (None, false) => TypeRef::named(scalar_type(type_name)),
+    }
+}
+
+/// The scalar used to filter and sort on a column, if it supports comparisons.
+fn comparable_type(type_name: &str) -> Option<&'static str> {
+    match scalar_type(type_name) {
+        _ if type_name.ends_with("[]") => None,
+        JSON => None,
+        scalar => Some(scalar),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::{Column, ForeignKey};
+
+    fn table(name: &str, columns: &[(&str, &str)], foreign_keys: Vec<ForeignKey>) -> Table {
+        Table {
+            schema: "public".to_string(),
+            name: name.to_string(),
+            kind: TableKind::Table,
+            columns: columns
+                .iter()
+                .map(|(name, type_name)| Column {
+                    name: name.to_string(),
+                    type_name: type_name.to_string(),
+                    nullable: *name != "id",
+                })
+                .collect(),
+            primary_key: vec!["id".to_string()],
+            foreign_keys,
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(type_name("public", "users"), "users");
+        assert_eq!(type_name("sales", "orders"), "sales_orders");
+        assert!(is_valid_name("_private2"));
+        assert!(!is_valid_name("2fa"));
+        assert!(!is_valid_name("order-items"));
+        assert!(!is_valid_name("__type"));
+        assert!(!is_valid_name(""));
+    }
+
+    #[test]
+    fn test_value_types() {
+        assert_eq!(value_type("integer", true).to_string(), "Int!");
+        assert_eq!(value_type("bigint", false).to_string(), "BigInt");
+        assert_eq!(value_type("text[]", false).to_string(), "[String]");
+        assert_eq!(value_type("jsonb", false).to_string(), "JSON");
+        assert_eq!(value_type("timestamp with time zone", true).to_string(), "String!");
+        assert_eq!(comparable_type("numeric"), Some(TypeRef::STRING));
+        assert_eq!(comparable_type("jsonb"), None);
+        assert_eq!(comparable_type("integer[]"), None);
+    }
+
+    #[test]
+    fn test_schema() {
+        let users = table("users", &[("id", "integer"), ("name", "text")], Vec::new());
+        let orders = table(
+            "orders",
+            &[("id", "integer"), ("user_id", "integer"), ("data", "jsonb")],
+            vec![ForeignKey {
+                name: "orders_user_id_fkey".to_string(),
+                columns: vec!["user_id".to_string()],
+                referenced_schema: "public".to_string(),
+                referenced_table: "users".to_string(),
+                referenced_columns: vec!["id".to_string()],
+            }],
+        );
+        let sdl = schema_builder(&[&users, &orders], &[])
+            .unwrap()
+            .finish()
+            .unwrap()
+            .sdl();
+
+        assert!(sdl.contains(
+            "users(filter: users_filter, order_by: [users_order_by!], limit: Int, offset: Int): [users!]!"
+        ));
+        assert!(sdl.contains("users_by_pk(id: Int!): users"));
+        assert!(sdl.contains("insert_orders(objects: [orders_input!]!): [orders!]!"));
+        assert!(sdl.contains("update_users_by_pk(id: Int!, set: users_input!): users"));
+        // The foreign key is followed in both directions
+        assert!(sdl.contains("users: users\n"));
+        assert!(sdl.contains(
+            "orders(filter: orders_filter, order_by: [orders_order_by!], limit: Int, offset: Int): [orders!]!"
+        ));
+        assert!(sdl.contains("data: JSON\n"));
+        assert!(!sdl.contains("data: JSON_comparison"));
+
+        assert!(schema_builder(&[], &[]).is_none());
+    }
+
+    #[test]
+    fn test_functions_follow_rpc_config() {
+        let function = |schema: &str, name: &str| Routine {
+            schema: schema.to_string(),
+            name: name.to_string(),
+            is_procedure: false,
+            returns: Returns::Scalar,
+            is_volatile: false,
+            return_type: "integer".to_string(),
+            args: Vec::new(),
+        };
+        let functions = [function("public", "answer"), function("internal", "secret")];
+        let rpc = RpcConfig {
+            enabled: Some(true),
+            schemas: None,
+        };
+
+        let exposed = callable_functions(&functions, Some(&rpc));
+        let sdl = schema_builder(&[], &exposed)
+            .unwrap()
+            .finish()
+            .unwrap()
+            .sdl();
+        assert!(sdl.contains("answer: Int"));
+        assert!(!sdl.contains("secret"));
+
+        // Without /rpc no function is exposed
+        assert!(callable_functions(&functions, None).is_empty());
+    }
+}
@@ -0,0 +1,433 @@
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio_postgres::GenericClient;
+use tracing::info;
+
+use crate::catalog::{TableKind, FOREIGN_KEYS_QUERY};
+use crate::oidc::AuthenticatedUser;
+use crate::{api_error, begin_session, db_error, get_client, mapped_role, ApiError, AppState};
+
+/// Condition on `pg_namespace n` leaving out system schemas and schemas the current
+/// role cannot use.
+const VISIBLE_SCHEMA: &str = "
+    n.nspname <> 'information_schema'
+    AND n.nspname NOT LIKE 'pg\\_%'
+    AND has_schema_privilege(n.oid, 'USAGE')";
+
+const SCHEMAS_QUERY: &str = "
+    SELECT n.nspname::text
+    FROM pg_namespace n
+    WHERE {visible}
+    ORDER BY n.nspname";
+
+/// Columns the current role has any privilege on, with one row per column.
+const COLUMNS_QUERY: &str = "
+    SELECT n.nspname::text,
+           c.relname::text,
+           c.relkind::text,
+           a.attname::text,
+           format_type(a.atttypid, a.atttypmod) AS type_name,
+           NOT a.attnotnull AS nullable,
+           pg_get_expr(d.adbin, d.adrelid) AS default_value,
+           coalesce(a.attnum = ANY(pk.indkey), false) AS is_primary_key
+    FROM pg_class c
+    JOIN pg_namespace n ON n.oid = c.relnamespace
+    JOIN pg_attribute a ON a.attrelid = c.oid AND a.attnum > 0 AND NOT a.attisdropped
+    LEFT JOIN pg_attrdef d ON d.adrelid = c.oid AND d.adnum = a.attnum
+    LEFT JOIN pg_index pk ON pk.indrelid = c.oid AND pk.indisprimary
+    WHERE c.relkind IN ('r', 'p', 'v', 'm', 'f')
+      AND NOT c.relispartition
+      AND {visible}
+      AND has_column_privilege(c.oid, a.attnum, 'SELECT, INSERT, UPDATE, REFERENCES')
+    ORDER BY n.nspname, c.relname, a.attnum";
+
+/// Indexes with their key columns or expressions in index order.
+const INDEXES_QUERY: &str = "
+    SELECT n.nspname::text,
+           t.relname::text,
+           i.relname::text AS index_name,
+           x.indisunique,
+           x.indisprimary,
+           ARRAY(
+               SELECT pg_get_indexdef(x.indexrelid, k, true)
+               FROM generate_series(1, x.indnkeyatts::int) AS k
+               ORDER BY k
+           ) AS columns,
+           pg_get_indexdef(x.indexrelid) AS definition
+    FROM pg_index x
+    JOIN pg_class i ON i.oid = x.indexrelid
+    JOIN pg_class t ON t.oid = x.indrelid
+    JOIN pg_namespace n ON n.oid = t.relnamespace
+    WHERE {visible}
+    ORDER BY n.nspname, t.relname, i.relname";
+
+/// Functions, procedures and aggregates the current role may execute.
+const FUNCTIONS_QUERY: &str = "
+    SELECT n.nspname::text,
+           p.proname::text,
+           CASE p.prokind
+               WHEN 'p' THEN 'procedure'
+               WHEN 'a' THEN 'aggregate'
+               WHEN 'w' THEN 'window'
+               ELSE 'function'
+           END AS kind,
+           pg_get_function_arguments(p.oid) AS arguments,
+           pg_get_function_result(p.oid) AS returns,
+           CASE p.provolatile
+               WHEN 'i' THEN 'immutable'
+               WHEN 's' THEN 'stable'
+               ELSE 'volatile'
+           END AS volatility
+    FROM pg_proc p
+    JOIN pg_namespace n ON n.oid = p.pronamespace
+    WHERE {visible}
+      AND has_function_privilege(p.oid, 'EXECUTE')
+    ORDER BY n.nspname, p.proname, arguments";
+
+/// The schemas, tables and functions visible to one role.
+#[derive(Debug, Serialize)]
+pub struct DatabaseSchema {
+    pub schemas: Vec<SchemaInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchemaInfo {
+    pub name: String,
+    pub tables: Vec<TableInfo>,
+    pub functions: Vec<FunctionInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TableInfo {
+    pub name: String,
+    pub kind: TableKind,
+    /// Only the columns the role has a privilege on
+    pub columns: Vec<ColumnInfo>,
+    pub primary_key: Vec<String>,
+    pub indexes: Vec<IndexInfo>,
+    pub foreign_keys: Vec<ForeignKeyInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ColumnInfo {
+    pub name: String,
+    /// Type with modifiers, e.g. `character varying(255)`
+    #[serde(rename = "type")]
+    pub type_name: String,
+    pub nullable: bool,
+    /// Default expression, e.g. `nextval('users_id_seq'::regclass)`
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IndexInfo {
+    pub name: String,
+    /// Key columns, or expressions for expression indexes
+    pub columns: Vec<String>,
+    pub unique: bool,
+    pub primary: bool,
+    /// The `CREATE INDEX` statement
+    pub definition: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ForeignKeyInfo {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub referenced_schema: String,
+    pub referenced_table: String,
+    pub referenced_columns: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FunctionInfo {
+    pub name: String,
+    /// "function", "procedure", "aggregate" or "window"
+    pub kind: String,
+    /// Argument list as declared, e.g. `a integer, b integer DEFAULT 0`
+    pub arguments: String,
+    pub returns: Option<String>,
+    /// "immutable", "stable" or "volatile"
+    pub volatility: String,
+}
+
+/// Introspection results by mapped role, kept until refreshed.
+#[derive(Clone, Default)]
+pub struct SchemaCache {
+    entries: Arc<Mutex<HashMap<Option<String>, Arc<DatabaseSchema>>>>,
+}
+
+impl SchemaCache {
+    pub fn get(&self, role: Option<&str>) -> Option<Arc<DatabaseSchema>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&role.map(str::to_string))
+            .cloned()
+    }
+
+    pub fn insert(&self, role: Option<String>, schema: Arc<DatabaseSchema>) {
+        self.entries.lock().unwrap().insert(role, schema);
+    }
+
+    pub fn remove(&self, role: Option<&str>) {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(&role.map(str::to_string));
+    }
+}
+
+/// Reads the schemas visible to the current role; run it after switching to the role.
+pub async fn load<C: GenericClient>(client: &C) -> Result<DatabaseSchema, tokio_postgres::Error> {
+    let query = |sql: &str| sql.replace("{visible}", VISIBLE_SCHEMA.trim());
+
+    let mut schemas: BTreeMap<String, SchemaInfo> = BTreeMap::new();
+    for row in client.query(&query(SCHEMAS_QUERY), &[]).await? {
+        let name: String = row.get("nspname");
+        schemas.insert(
+            name.clone(),
+            SchemaInfo {
+                name,
+                tables: Vec::new(),
+                functions: Vec::new(),
+            },
+        );
+    }
+
+    let mut tables: BTreeMap<(String, String), TableInfo> = BTreeMap::new();
+    for row in client.query(&query(COLUMNS_QUERY), &[]).await? {
+        let table = tables
+            .entry((row.get("nspname"), row.get("relname")))
+            .or_insert_with(|| TableInfo {
+                name: row.get("relname"),
+                kind: TableKind::from_relkind(row.get("relkind")),
+                columns: Vec::new(),
+                primary_key: Vec::new(),
+                indexes: Vec::new(),
+                foreign_keys: Vec::new(),
+            });
+        let column = ColumnInfo {
+            name: row.get("attname"),
+            type_name: row.get("type_name"),
+            nullable: row.get("nullable"),
+            default: row.get("default_value"),
+        };
+        if row.get("is_primary_key") {
+            table.primary_key.push(column.name.clone());
+        }
+        table.columns.push(column);
+    }
+
+    for row in client.query(&query(INDEXES_QUERY), &[]).await? {
+        if let Some(table) = tables.get_mut(&(row.get("nspname"), row.get("relname"))) {
+            table.indexes.push(IndexInfo {
+                name: row.get("index_name"),
+                columns: row.get("columns"),
+                unique: row.get("indisunique"),
+                primary: row.get("indisprimary"),
+                definition: row.get("definition"),
+            });
+        }
+    }
+
+    for row in client.query(FOREIGN_KEYS_QUERY, &[]).await? {
+        let foreign_key = ForeignKeyInfo {
+            name: row.get("conname"),
+            columns: row.get("columns"),
+            referenced_schema: row.get("referenced_schema"),
+            referenced_table: row.get("referenced_table"),
+            referenced_columns: row.get("referenced_columns"),
+        };
+        // Only keys between tables the role can see are listed
+        let referenced = (
+            foreign_key.referenced_schema.clone(),
+            foreign_key.referenced_table.clone(),
+        );
+        if !tables.contains_key(&referenced) {
+            continue;
+        }
+        if let Some(table) = tables.get_mut(&(row.get("nspname"), row.get("relname"))) {
+            table.foreign_keys.push(foreign_key);
+        }
+    }
+
+    for ((schema, _), table) in tables {
+        if let Some(schema) = schemas.get_mut(&schema) {
+            schema.tables.push(table);
+        }
+    }
+
+    for row in client.query(&query(FUNCTIONS_QUERY), &[]).await? {
+        if let Some(schema) = schemas.get_mut(row.get::<_, &str>("nspname")) {
+            schema.functions.push(FunctionInfo {
+                name: row.get("proname"),
+                kind: row.get("kind"),
+                arguments: row.get("arguments"),
+                returns: row.get("returns"),
+                volatility: row.get("volatility"),
+            });
+        }
+    }
+
+    Ok(DatabaseSchema {
+        schemas: schemas.into_values().collect(),
+    })
+}
+
+/// Lists every schema visible to the caller's role, with its tables and functions.
+pub async fn get_schema(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    info!("Reading database schema for user {}", user.sub);
+
+    let schema = cached_schema(&state, &user).await?;
+    Ok(Json(&*schema).into_response())
+}
+
+pub async fn get_namespace(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Path(name): Path<String>,
+) -> Result<Response, ApiError> {
+    info!("Reading schema {} for user {}", name, user.sub);
+
+    let schema = cached_schema(&state, &user).await?;
+    let namespace = find_namespace(&schema, &name)?;
+    Ok(Json(namespace).into_response())
+}
+
+pub async fn get_table(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Path((name, table)): Path<(String, String)>,
+) -> Result<Response, ApiError> {
+    info!("Reading table {}.{} for user {}", name, table, user.sub);
+
+    let schema = cached_schema(&state, &user).await?;
+    let table = find_namespace(&schema, &name)?
+        .tables
+        .iter()
+        .find(|t| t.name == table)
+        .ok_or_else(|| {
+            api_error(
+                StatusCode::NOT_FOUND,
+                format!("Table {}.{} not found", name, table),
+            )
+        })?;
+    Ok(Json(table).into_response())
+}
+
+/// Drops the cached results of the caller's role and returns them, read again.
+pub async fn refresh_schema(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> Result<Response, ApiError> {
+    info!("Refreshing database schema for user {}", user.sub);
+
+    // Only the caller's role, so one user cannot make every role's next request slow
+    let role = mapped_role(&state, &user)?;
+    state.schema_cache.remove(role.as_deref());
+    let schema = cached_schema(&state, &user).await?;
+    Ok(Json(&*schema).into_response())
+}
+
+fn find_namespace<'a>(schema: &'a DatabaseSchema, name: &str) -> Result<&'a SchemaInfo, ApiError> {
+    schema
+        .schemas
+        .iter()
+        .find(|namespace| namespace.name == name)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Schema {} not found", name)))
+}
+
+async fn cached_schema(
+    state: &AppState,
+    user: &AuthenticatedUser,
+) -> Result<Arc<DatabaseSchema>, ApiError> {
+    let role = mapped_role(state, user)?;
+    if let Some(schema) = state.schema_cache.get(role.as_deref()) {
+        return Ok(schema);
+    }
+
+    let mut client = get_client(state).await?;
+    let transaction = begin_session(&mut client, role.as_deref(), user, true).await?;
+    let schema = load(&transaction)
+        .await
+        .map_err(|e| db_error("Schema introspection failed", &e))?;
+    transaction
+        .commit()
+        .await
+        .map_err(|e| db_error("Schema introspection failed", &e))?;
+
+    let schema = Arc::new(schema);
+    state.schema_cache.insert(role, schema.clone());
+    Ok(schema)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema(name: &str) -> Arc<DatabaseSchema> {
+        Arc::new(DatabaseSchema {
+            schemas: vec![SchemaInfo {
+                name: name.to_string(),
+                tables: Vec::new(),
+                functions: Vec::new(),
+            }],
+        })
+    }
+
+    #[test]
+    fn test_cache_by_role() {
+        let cache = SchemaCache::default();
+        cache.insert(Some("analyst".to_string()), schema("reports"));
+        cache.insert(None, schema("public"));
+
+        assert_eq!(
+            cache.get(Some("analyst")).unwrap().schemas[0].name,
+            "reports"
+        );
+        assert_eq!(cache.get(None).unwrap().schemas[0].name, "public");
+        assert!(cache.get(Some("admin")).is_none());
+
+        cache.remove(Some("analyst"));
+        assert!(cache.get(Some("analyst")).is_none());
+        assert_eq!(cache.get(None).unwrap().schemas[0].name, "public");
+    }
+
+    #[test]
+    fn test_serialize_table() {
+        let table = TableInfo {
+            name: "users".to_string(),
+            kind: TableKind::MaterializedView,
+            columns: vec![ColumnInfo {
+                name: "id".to_string(),
+                type_name: "integer".to_string(),
+                nullable: false,
+                default: None,
+            }],
+            primary_key: Vec::new(),
+            indexes: Vec::new(),
+            foreign_keys: Vec::new(),
+        };
+        assert_eq!(
+            serde_json::to_value(&table).unwrap(),
+            json!({
+                "name": "users",
+                "kind": "materialized_view",
+                "columns": [{"name": "id", "type": "integer", "nullable": false, "default": null}],
+                "primary_key": [],
+                "indexes": [],
+                "foreign_keys": []
+            })
+        );
+    }
+}
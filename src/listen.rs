@@ -0,0 +1,133 @@
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::{stream, Stream};
+use std::convert::Infallible;
+use tracing::{debug, info, warn};
+
+use crate::config::ListenConfig;
+use crate::oidc::{AuthenticatedUser, Claims};
+use crate::session;
+use crate::{api_error, db_error, ApiError, AppState};
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 10;
+
+/// Streams the notifications sent to `channel` as server-sent events, one event per
+/// notification with the payload as its data.
+pub async fn listen(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Path(channel): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    info!("Listening on channel {} for user {}", channel, user.sub);
+
+    if !is_allowed(state.config.listen.as_ref(), &channel, &user) {
+        warn!(
+            "User {} is not allowed to listen on channel {}",
+            user.sub, channel
+        );
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            format!("Not allowed to listen on channel {}", channel),
+        ));
+    }
+
+    let permit = state.listeners.clone().try_acquire_owned().map_err(|_| {
+        warn!("No listener connection available for user {}", user.sub);
+        api_error(StatusCode::SERVICE_UNAVAILABLE, "Too many listeners")
+    })?;
+    let listener = state.postgres_pool.listener().await.map_err(|e| {
+        warn!("Failed to open listener connection: {}", e);
+        api_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Database connection failed",
+        )
+    })?;
+    listener
+        .listen(&channel)
+        .await
+        .map_err(|e| db_error("Failed to listen", &e))?;
+
+    // The listener and its permit live as long as the client stays connected
+    let events = stream::unfold((listener, permit), move |(mut listener, permit)| {
+        let channel = channel.clone();
+        async move {
+            let Some(notification) = listener.next().await else {
+                debug!("Listener connection for channel {} closed", channel);
+                return None;
+            };
+            let event = Event::default().data(notification.payload());
+            Some((Ok(event), (listener, permit)))
+        }
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Checks whether any rule for `channel` admits the caller.
+fn is_allowed(config: Option<&ListenConfig>, channel: &str, claims: &Claims) -> bool {
+    let Some(config) = config else {
+        return false;
+    };
+    config
+        .channels
+        .iter()
+        .filter(|rule| rule.channel == channel)
+        .any(|rule| match (&rule.claim, &rule.value) {
+            (Some(claim), Some(value)) => session::claim_matches(claims, claim, value),
+            (None, None) => true,
+            // A claim without a value, or the reverse, admits no one
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ChannelRule;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+
+    fn claims(other: Value) -> Claims {
+        Claims {
+            sub: "user-1".to_string(),
+            iss: "https://issuer".to_string(),
+            aud: None,
+            exp: 0,
+            iat: 0,
+            other: serde_json::from_value::<HashMap<String, Value>>(other).unwrap(),
+        }
+    }
+
+    fn rule(channel: &str, claim: Option<&str>, value: Option<&str>) -> ChannelRule {
+        ChannelRule {
+            channel: channel.to_string(),
+            claim: claim.map(str::to_string),
+            value: value.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let config = ListenConfig {
+            channels: vec![
+                rule("order_updates", Some("groups"), Some("dashboards")),
+                rule("order_updates", Some("groups"), Some("admins")),
+                rule("announcements", None, None),
+                rule("audit", Some("groups"), None),
+            ],
+            max_connections: None,
+        };
+        let dashboard = claims(json!({"groups": ["dashboards"]}));
+        let admin = claims(json!({"groups": "admins"}));
+        let other = claims(json!({"groups": ["analysts"]}));
+
+        assert!(is_allowed(Some(&config), "order_updates", &dashboard));
+        assert!(is_allowed(Some(&config), "order_updates", &admin));
+        assert!(!is_allowed(Some(&config), "order_updates", &other));
+        assert!(is_allowed(Some(&config), "announcements", &other));
+        assert!(!is_allowed(Some(&config), "audit", &admin));
+        assert!(!is_allowed(Some(&config), "unlisted", &admin));
+        assert!(!is_allowed(None, "announcements", &admin));
+    }
+}
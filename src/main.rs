@@ -1,9 +1,9 @@
 use anyhow::Result;
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
     middleware,
-    response::Json,
+    response::{IntoResponse, Json, Response},
     routing::{get, post},
     Router,
 };
@@ -12,34 +12,194 @@ use std::sync::Arc;
 use tower_http::cors::CorsLayer;
 use tracing::{info, warn};
 
+mod catalog;
+mod columnar;
 mod config;
+mod format;
+mod graphql;
+mod introspection;
+mod listen;
 mod oidc;
+mod openapi;
+mod policy;
 mod postgres;
+mod queries;
+mod rest;
+mod rpc;
+mod session;
+mod sql;
+mod stream;
+mod tls;
+mod transactions;
+mod types;
 
+use catalog::Catalog;
 use config::Config;
-use oidc::OidcValidator;
-use postgres::PostgresPool;
+use format::{CsvOptions, ResponseFormat};
+use introspection::SchemaCache;
+use oidc::{AuthenticatedUser, OidcValidator};
+use policy::PolicyError;
+use postgres::{PostgresClient, PostgresPool};
+use queries::QueryRegistry;
+use tokio_postgres::error::{ErrorPosition, SqlState};
+use stream::StreamSession;
+use tokio_postgres::{Client, GenericClient, Transaction};
+use transactions::{
+    BeginError, PinnedTransaction, TransactionGuard, TransactionRegistry, TRANSACTION_ID_HEADER,
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct AppState {
+    pub config: Arc<Config>,
     pub postgres_pool: PostgresPool,
     pub oidc_validator: Arc<OidcValidator>,
+    pub transactions: TransactionRegistry,
+    pub queries: Arc<QueryRegistry>,
+    pub catalog: Arc<Catalog>,
+    /// Schema served at `/graphql`, if enabled
+    pub graphql: Option<async_graphql::dynamic::Schema>,
+    /// Results of `/schema`, by mapped role
+    pub schema_cache: SchemaCache,
+    /// Document served at `/openapi.json`
+    pub openapi: Arc<utoipa::openapi::OpenApi>,
+    /// Dedicated connections still available to `/listen`
+    pub listeners: Arc<tokio::sync::Semaphore>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct QueryRequest {
     sql: String,
+    /// Values bound to `$1..$n`
+    #[serde(default)]
+    params: Vec<serde_json::Value>,
+    /// Result format for `/query`; overrides the `Accept` header
+    #[serde(default)]
+    format: Option<ResponseFormat>,
+    #[serde(default)]
+    csv: CsvOptions,
+    /// Shape of the rows in a JSON response
+    #[serde(default)]
+    shape: RowShape,
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum RowShape {
+    /// Each row is an object keyed by column name
+    #[default]
+    Objects,
+    /// Each row is an array of values in column order, described by `columns`
+    Arrays,
+}
+
+#[derive(Serialize, ToSchema)]
 struct QueryResponse {
+    /// Column descriptions, present when rows are returned as arrays
+    #[serde(skip_serializing_if = "Option::is_none")]
+    columns: Option<Vec<ColumnInfo>>,
+    /// Objects keyed by column name, or arrays of values in column order
     rows: Vec<serde_json::Value>,
+    /// Number of rows a statement without a result set affected
     rows_affected: Option<u64>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
+struct ColumnInfo {
+    name: String,
+    /// PostgreSQL type name, e.g. "int4" or "timestamptz"
+    #[serde(rename = "type")]
+    type_name: String,
+    type_oid: u32,
+    /// OID of the table the column comes from, if it is a plain table column
+    table_oid: Option<u32>,
+    /// Attribute number of the column within that table
+    column_number: Option<i16>,
+}
+
+impl From<&tokio_postgres::Column> for ColumnInfo {
+    fn from(column: &tokio_postgres::Column) -> Self {
+        Self {
+            name: column.name().to_string(),
+            type_name: column.type_().name().to_string(),
+            type_oid: column.type_().oid(),
+            table_oid: column.table_oid(),
+            column_number: column.column_id(),
+        }
+    }
+}
+
+#[derive(Serialize, Default, ToSchema)]
 struct ErrorResponse {
     error: String,
+    /// SQLSTATE of the database error, e.g. "23505"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<String>,
+    /// 1-based character offset of the error in the SQL text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schema: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    table: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    constraint: Option<String>,
+    /// Index of the failing statement in a `/batch` request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    statement_index: Option<usize>,
+    /// Name of the SQL policy rule that rejected the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule: Option<String>,
+}
+
+impl ErrorResponse {
+    fn new(error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    statements: Vec<BatchStatement>,
+}
+
+#[derive(Deserialize)]
+struct BatchStatement {
+    sql: String,
+    #[serde(default)]
+    params: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct BeginTransactionResponse {
+    transaction_id: Uuid,
+    /// The transaction is rolled back after this many seconds without requests
+    idle_timeout_seconds: u64,
+}
+
+#[derive(Serialize)]
+struct TransactionStatusResponse {
+    transaction_id: Uuid,
+    /// "committed" or "rolled_back"
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+    /// One result per statement, in request order
+    results: Vec<QueryResponse>,
 }
 
 async fn health_check() -> Json<serde_json::Value> {
@@ -49,90 +209,626 @@ async fn health_check() -> Json<serde_json::Value> {
     }))
 }
 
+/// An error response returned by the handlers.
+struct ApiError(StatusCode, Box<ErrorResponse>);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(*self.1)).into_response()
+    }
+}
+
+impl ApiError {
+    /// Records which statement of a batch failed.
+    fn at_statement(mut self, index: usize) -> Self {
+        self.1.error = format!("Statement {} failed: {}", index, self.1.error);
+        self.1.statement_index = Some(index);
+        self
+    }
+}
+
+fn api_error(status: StatusCode, error: impl Into<String>) -> ApiError {
+    ApiError(status, Box::new(ErrorResponse::new(error)))
+}
+
+/// Builds an error response from a database error, keeping its SQLSTATE and diagnostics.
+fn db_error(context: &str, e: &tokio_postgres::Error) -> ApiError {
+    warn!("{}: {}", context, e);
+
+    let Some(db) = e.as_db_error() else {
+        if e.is_closed() {
+            return api_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("{}: database connection lost", context),
+            );
+        }
+        return api_error(StatusCode::INTERNAL_SERVER_ERROR, format!("{}: {}", context, e));
+    };
+
+    let position = match db.position() {
+        Some(ErrorPosition::Original(position)) => Some(*position),
+        _ => None,
+    };
+
+    ApiError(
+        status_for_sqlstate(db.code()),
+        Box::new(ErrorResponse {
+            error: format!("{}: {}", context, db.message()),
+            code: Some(db.code().code().to_string()),
+            message: Some(db.message().to_string()),
+            detail: db.detail().map(str::to_string),
+            hint: db.hint().map(str::to_string).or_else(|| {
+                (db.code() == &SqlState::READ_ONLY_SQL_TRANSACTION)
+                    .then(|| "/query is read-only; use /execute to modify data".to_string())
+            }),
+            position,
+            schema: db.schema().map(str::to_string),
+            table: db.table().map(str::to_string),
+            column: db.column().map(str::to_string),
+            constraint: db.constraint().map(str::to_string),
+            statement_index: None,
+            rule: None,
+        }),
+    )
+}
+
+fn status_for_sqlstate(code: &SqlState) -> StatusCode {
+    match code.code() {
+        // unique_violation, foreign_key_violation, serialization_failure, deadlock_detected
+        "23505" | "23503" | "40001" | "40P01" => StatusCode::CONFLICT,
+        // in_failed_sql_transaction: an interactive transaction was aborted by an earlier error
+        "25P02" => StatusCode::CONFLICT,
+        // insufficient_privilege, read_only_sql_transaction (writes through /query)
+        "42501" | "25006" => StatusCode::FORBIDDEN,
+        // query_canceled (including statement_timeout)
+        "57014" => StatusCode::REQUEST_TIMEOUT,
+        // connection exceptions, insufficient resources, server shutting down or starting up
+        c if c.starts_with("08") || c.starts_with("53") => StatusCode::SERVICE_UNAVAILABLE,
+        "57P01" | "57P02" | "57P03" => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Resolves the PostgreSQL role for the caller, rejecting users without a mapping.
+fn mapped_role(state: &AppState, user: &AuthenticatedUser) -> Result<Option<String>, ApiError> {
+    session::resolve_role(state.config.role_mapping.as_ref(), user).map_err(|e| {
+        warn!("{}", e);
+        api_error(StatusCode::FORBIDDEN, e.to_string())
+    })
+}
+
+/// Rejects SQL that the configured policy rules do not allow for the caller.
+fn enforce_policy(
+    state: &AppState,
+    user: &AuthenticatedUser,
+    role: Option<&str>,
+    sql: &str,
+) -> Result<(), ApiError> {
+    match policy::check(state.config.sql_policy.as_ref(), role, user, sql) {
+        Ok(()) => Ok(()),
+        Err(e @ PolicyError::Parse(_)) => {
+            warn!("Rejected SQL from user {}: {}", user.sub, e);
+            Err(api_error(StatusCode::BAD_REQUEST, e.to_string()))
+        }
+        Err(PolicyError::Blocked { rule, reason }) => {
+            warn!(
+                "SQL policy rule '{}' blocked SQL from user {}: {}",
+                rule, user.sub, reason
+            );
+            let mut error = ErrorResponse::new(format!(
+                "Blocked by SQL policy rule '{}': {}",
+                rule, reason
+            ));
+            error.rule = Some(rule);
+            Err(ApiError(StatusCode::FORBIDDEN, Box::new(error)))
+        }
+    }
+}
+
+/// Rejects SQL that would end the caller's transaction, change the role it runs as or
+/// overwrite the `request.jwt.*` settings row-level security policies read.
+fn enforce_session(user: &AuthenticatedUser, sql: &str) -> Result<(), ApiError> {
+    match policy::session_violation(sql) {
+        Some(reason) => {
+            warn!("Rejected SQL from user {}: {}", user.sub, reason);
+            Err(api_error(StatusCode::BAD_REQUEST, reason))
+        }
+        None => Ok(()),
+    }
+}
+
+async fn get_client(state: &AppState) -> Result<PostgresClient, ApiError> {
+    state.postgres_pool.get_client().await.map_err(|e| {
+        warn!("Failed to get database client: {}", e);
+        api_error(StatusCode::SERVICE_UNAVAILABLE, "Database connection failed")
+    })
+}
+
+async fn begin_session<'a>(
+    client: &'a mut PostgresClient,
+    role: Option<&str>,
+    user: &AuthenticatedUser,
+    read_only: bool,
+) -> Result<Transaction<'a>, ApiError> {
+    session::begin(client, role, user, read_only)
+        .await
+        .map_err(|e| db_error("Failed to start database session", &e))
+}
+
+/// Looks up the interactive transaction named by the `X-Transaction-Id` header, if any.
+fn pinned_transaction(
+    state: &AppState,
+    headers: &HeaderMap,
+    user: &AuthenticatedUser,
+) -> Result<Option<Arc<PinnedTransaction>>, ApiError> {
+    let Some(value) = headers.get(TRANSACTION_ID_HEADER) else {
+        return Ok(None);
+    };
+    let id = value
+        .to_str()
+        .ok()
+        .and_then(|value| Uuid::parse_str(value).ok())
+        .ok_or_else(|| api_error(StatusCode::BAD_REQUEST, "Invalid X-Transaction-Id header"))?;
+
+    owned_transaction(state, &id, user).map(Some)
+}
+
+fn owned_transaction(
+    state: &AppState,
+    id: &Uuid,
+    user: &AuthenticatedUser,
+) -> Result<Arc<PinnedTransaction>, ApiError> {
+    let transaction = state
+        .transactions
+        .get(id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Transaction {} not found", id)))?;
+    if transaction.owner() != user.sub {
+        warn!("User {} tried to use transaction {} of another user", user.sub, id);
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            format!("Transaction {} belongs to another user", id),
+        ));
+    }
+    Ok(transaction)
+}
+
+async fn lock_transaction(transaction: &PinnedTransaction) -> Result<TransactionGuard, ApiError> {
+    transaction
+        .lock()
+        .await
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Transaction has already ended"))
+}
+
 async fn execute_query(
     State(state): State<AppState>,
+    user: AuthenticatedUser,
+    headers: HeaderMap,
     Json(query_req): Json<QueryRequest>,
-) -> Result<Json<QueryResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let client = state
-        .postgres_pool
-        .get_client()
-        .await
-        .map_err(|e| {
-            warn!("Failed to get database client: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: "Database connection failed".to_string(),
-                }),
-            )
-        })?;
+) -> Result<Response, ApiError> {
+    info!("Executing query for user {}", user.sub);
 
-    // Execute the query
-    match client.query(&query_req.sql, &[]).await {
-        Ok(rows) => {
-            let mut result_rows = Vec::new();
-            for row in rows {
-                let mut json_row = serde_json::Map::new();
-                for (i, column) in row.columns().iter().enumerate() {
-                    let value: Option<String> = row.try_get(i).ok();
-                    json_row.insert(
-                        column.name().to_string(),
-                        value.map(serde_json::Value::String).unwrap_or(serde_json::Value::Null),
-                    );
-                }
-                result_rows.push(serde_json::Value::Object(json_row));
-            }
-            Ok(Json(QueryResponse {
-                rows: result_rows,
-                rows_affected: None,
-            }))
-        }
-        Err(e) => {
-            warn!("Query execution failed: {}", e);
-            Err((
-                StatusCode::BAD_REQUEST,
-                Json(ErrorResponse {
-                    error: format!("Query execution failed: {}", e),
-                }),
-            ))
+    let format = ResponseFormat::negotiate(query_req.format, &headers);
+    let encoder = format
+        .encoder(&query_req.csv)
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let role = mapped_role(&state, &user)?;
+    enforce_policy(&state, &user, role.as_deref(), &query_req.sql)?;
+    enforce_session(&user, &query_req.sql)?;
+
+    if let Some(transaction) = pinned_transaction(&state, &headers, &user)? {
+        let guard = lock_transaction(&transaction).await?;
+        if let Some(encoder) = encoder {
+            let session = StreamSession::Pinned(guard);
+            return stream::stream_query(session, query_req.sql, query_req.params, encoder).await;
         }
+        return Ok(Json(run_read_only_query(guard, query_req).await?).into_response());
     }
+
+    let mut client = get_client(&state).await?;
+
+    if let Some(encoder) = encoder {
+        let AuthenticatedUser(claims) = user;
+        let session = StreamSession::New {
+            client,
+            role,
+            claims,
+        };
+        return stream::stream_query(session, query_req.sql, query_req.params, encoder).await;
+    }
+
+    let transaction = begin_session(&mut client, role.as_deref(), &user, true).await?;
+    let response = run_query(&transaction, &query_req).await?;
+    transaction
+        .commit()
+        .await
+        .map_err(|e| db_error("Query execution failed", &e))?;
+
+    Ok(Json(response).into_response())
+}
+
+/// Runs a query inside an interactive transaction without letting it write.
+///
+/// The query runs on its own task, so the transaction is restored even if the client
+/// disconnects and the request is dropped halfway.
+async fn run_read_only_query(
+    guard: TransactionGuard,
+    query_req: QueryRequest,
+) -> Result<QueryResponse, ApiError> {
+    let task = tokio::spawn(async move {
+        let client: &Client = &guard;
+        session::begin_read_only(client)
+            .await
+            .map_err(|e| db_error("Query execution failed", &e))?;
+        let response = run_query(client, &query_req).await;
+        session::end_read_only(client)
+            .await
+            .map_err(|e| db_error("Query execution failed", &e))?;
+        response
+    });
+    task.await.map_err(|e| {
+        warn!("Read-only query task failed: {}", e);
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query execution failed: query task ended unexpectedly",
+        )
+    })?
+}
+
+async fn run_query<C: GenericClient>(
+    client: &C,
+    query_req: &QueryRequest,
+) -> Result<QueryResponse, ApiError> {
+    let statement = types::prepare(client, &query_req.sql)
+        .await
+        .map_err(|e| db_error("Query execution failed", &e))?;
+
+    let params = types::bind_params(statement.params(), &query_req.params).map_err(|e| {
+        warn!("Invalid query parameters: {}", e);
+        api_error(StatusCode::BAD_REQUEST, e.to_string())
+    })?;
+
+    // Execute the query
+    let rows = client
+        .query(&statement, &types::param_refs(&params))
+        .await
+        .map_err(|e| db_error("Query execution failed", &e))?;
+
+    let response = match query_req.shape {
+        RowShape::Objects => QueryResponse {
+            columns: None,
+            rows: rows
+                .iter()
+                .map(|row| serde_json::Value::Object(types::row_to_json(row)))
+                .collect(),
+            rows_affected: None,
+        },
+        RowShape::Arrays => QueryResponse {
+            columns: Some(statement.columns().iter().map(ColumnInfo::from).collect()),
+            rows: rows
+                .iter()
+                .map(|row| serde_json::Value::Array(types::row_to_array(row)))
+                .collect(),
+            rows_affected: None,
+        },
+    };
+    Ok(response)
 }
 
 async fn execute_mutation(
     State(state): State<AppState>,
+    user: AuthenticatedUser,
+    headers: HeaderMap,
     Json(query_req): Json<QueryRequest>,
-) -> Result<Json<QueryResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let client = state
-        .postgres_pool
-        .get_client()
-        .await
-        .map_err(|e| {
-            warn!("Failed to get database client: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: "Database connection failed".to_string(),
-                }),
-            )
-        })?;
+) -> Result<Json<QueryResponse>, ApiError> {
+    info!("Executing mutation for user {}", user.sub);
+
+    let role = mapped_role(&state, &user)?;
+    enforce_policy(&state, &user, role.as_deref(), &query_req.sql)?;
+    enforce_session(&user, &query_req.sql)?;
+
+    if let Some(transaction) = pinned_transaction(&state, &headers, &user)? {
+        let guard = lock_transaction(&transaction).await?;
+        return Ok(Json(run_mutation(&**guard, &query_req).await?));
+    }
+
+    let mut client = get_client(&state).await?;
+    let transaction = begin_session(&mut client, role.as_deref(), &user, false).await?;
+    let response = run_mutation(&transaction, &query_req).await?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(|e| db_error("Mutation execution failed", &e))?;
+
+    Ok(Json(response))
+}
+
+async fn run_mutation<C: GenericClient>(
+    client: &C,
+    query_req: &QueryRequest,
+) -> Result<QueryResponse, ApiError> {
+    let statement = client
+        .prepare(&query_req.sql)
+        .await
+        .map_err(|e| db_error("Mutation execution failed", &e))?;
+
+    let params = types::bind_params(statement.params(), &query_req.params).map_err(|e| {
+        warn!("Invalid mutation parameters: {}", e);
+        api_error(StatusCode::BAD_REQUEST, e.to_string())
+    })?;
 
     // Execute the mutation
-    match client.execute(&query_req.sql, &[]).await {
-        Ok(rows_affected) => Ok(Json(QueryResponse {
+    let rows_affected = client
+        .execute(&statement, &types::param_refs(&params))
+        .await
+        .map_err(|e| db_error("Mutation execution failed", &e))?;
+
+    Ok(QueryResponse {
+        columns: None,
+        rows: vec![],
+        rows_affected: Some(rows_affected),
+    })
+}
+
+async fn begin_transaction(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+) -> Result<(StatusCode, Json<BeginTransactionResponse>), ApiError> {
+    let role = mapped_role(&state, &user)?;
+    let too_many = || {
+        warn!("User {} has too many open transactions", user.sub);
+        api_error(StatusCode::TOO_MANY_REQUESTS, "Too many open transactions")
+    };
+    if !state.transactions.has_capacity(&user.sub) {
+        return Err(too_many());
+    }
+    let client = get_client(&state).await?;
+    let transaction_id = state
+        .transactions
+        .begin(client, role.as_deref(), &user)
+        .await
+        .map_err(|e| match e {
+            BeginError::TooMany => too_many(),
+            BeginError::Database(e) => db_error("Failed to start transaction", &e),
+        })?;
+
+    info!("Started transaction {} for user {}", transaction_id, user.sub);
+    Ok((
+        StatusCode::CREATED,
+        Json(BeginTransactionResponse {
+            transaction_id,
+            idle_timeout_seconds: state.transactions.idle_timeout().as_secs(),
+        }),
+    ))
+}
+
+async fn commit_transaction(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Path(id): Path<Uuid>,
+) -> Result<Json<TransactionStatusResponse>, ApiError> {
+    owned_transaction(&state, &id, &user)?;
+    let Some(transaction) = state.transactions.remove(&id) else {
+        return Err(api_error(StatusCode::NOT_FOUND, format!("Transaction {} not found", id)));
+    };
+
+    info!("Committing transaction {} for user {}", id, user.sub);
+    transaction
+        .commit()
+        .await
+        .map_err(|e| db_error("Transaction commit failed", &e))?;
+
+    Ok(Json(TransactionStatusResponse {
+        transaction_id: id,
+        status: "committed",
+    }))
+}
+
+async fn rollback_transaction(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Path(id): Path<Uuid>,
+) -> Result<Json<TransactionStatusResponse>, ApiError> {
+    owned_transaction(&state, &id, &user)?;
+    let Some(transaction) = state.transactions.remove(&id) else {
+        return Err(api_error(StatusCode::NOT_FOUND, format!("Transaction {} not found", id)));
+    };
+
+    info!("Rolling back transaction {} for user {}", id, user.sub);
+    transaction
+        .rollback()
+        .await
+        .map_err(|e| db_error("Transaction rollback failed", &e))?;
+
+    Ok(Json(TransactionStatusResponse {
+        transaction_id: id,
+        status: "rolled_back",
+    }))
+}
+
+async fn execute_batch(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Json(batch): Json<BatchRequest>,
+) -> Result<Json<BatchResponse>, ApiError> {
+    info!(
+        "Executing batch of {} statements for user {}",
+        batch.statements.len(),
+        user.sub
+    );
+
+    let role = mapped_role(&state, &user)?;
+    // Check every statement before any of them runs
+    for (index, statement) in batch.statements.iter().enumerate() {
+        enforce_session(&user, &statement.sql)
+            .and_then(|()| enforce_policy(&state, &user, role.as_deref(), &statement.sql))
+            .map_err(|e| e.at_statement(index))?;
+    }
+
+    let mut client = get_client(&state).await?;
+    let transaction = begin_session(&mut client, role.as_deref(), &user, false).await?;
+
+    let mut results = Vec::with_capacity(batch.statements.len());
+    for (index, statement) in batch.statements.iter().enumerate() {
+        // Returning early drops the transaction, which rolls back every statement
+        let result = run_statement(&transaction, &statement.sql, &statement.params)
+            .await
+            .map_err(|e| e.at_statement(index))?;
+        results.push(result);
+    }
+
+    transaction
+        .commit()
+        .await
+        .map_err(|e| db_error("Batch commit failed", &e))?;
+
+    Ok(Json(BatchResponse { results }))
+}
+
+/// Runs a batch statement or named query: statements that return columns (including
+/// `INSERT ... RETURNING`) produce rows, others their affected row count.
+async fn run_statement(
+    transaction: &Transaction<'_>,
+    sql: &str,
+    params: &[serde_json::Value],
+) -> Result<QueryResponse, ApiError> {
+    let statement = types::prepare(transaction, sql)
+        .await
+        .map_err(|e| db_error("Statement execution failed", &e))?;
+
+    let params = types::bind_params(statement.params(), params).map_err(|e| {
+        warn!("Invalid statement parameters: {}", e);
+        api_error(StatusCode::BAD_REQUEST, e.to_string())
+    })?;
+
+    if statement.columns().is_empty() {
+        let rows_affected = transaction
+            .execute(&statement, &types::param_refs(&params))
+            .await
+            .map_err(|e| db_error("Statement execution failed", &e))?;
+        return Ok(QueryResponse {
+            columns: None,
             rows: vec![],
             rows_affected: Some(rows_affected),
-        })),
-        Err(e) => {
-            warn!("Mutation execution failed: {}", e);
-            Err((
-                StatusCode::BAD_REQUEST,
-                Json(ErrorResponse {
-                    error: format!("Mutation execution failed: {}", e),
-                }),
-            ))
-        }
+        });
     }
+
+    let rows = transaction
+        .query(&statement, &types::param_refs(&params))
+        .await
+        .map_err(|e| db_error("Statement execution failed", &e))?;
+    Ok(QueryResponse {
+        columns: None,
+        rows: rows
+            .iter()
+            .map(|row| serde_json::Value::Object(types::row_to_json(row)))
+            .collect(),
+        rows_affected: None,
+    })
+}
+
+async fn run_named_query(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Path(name): Path<String>,
+    Json(args): Json<serde_json::Map<String, serde_json::Value>>,
+) -> Result<Json<QueryResponse>, ApiError> {
+    info!("Running named query {} for user {}", name, user.sub);
+
+    let query = state
+        .queries
+        .get(&name)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Named query {} not found", name)))?;
+
+    let role = mapped_role(&state, &user)?;
+    if !queries::is_allowed(query, role.as_deref(), &user) {
+        warn!("User {} is not allowed to run named query {}", user.sub, name);
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            format!("Not allowed to run named query {}", name),
+        ));
+    }
+
+    let params = queries::bind_args(query, &args).map_err(|e| {
+        warn!("Invalid arguments for named query {}: {}", name, e);
+        api_error(StatusCode::BAD_REQUEST, e.to_string())
+    })?;
+
+    let read_only = query.read_only.unwrap_or(true);
+    let mut client = get_client(&state).await?;
+    let transaction = begin_session(&mut client, role.as_deref(), &user, read_only).await?;
+    let response = run_statement(&transaction, &query.sql, &params).await?;
+    transaction
+        .commit()
+        .await
+        .map_err(|e| db_error("Statement execution failed", &e))?;
+
+    Ok(Json(response))
+}
+
+async fn call_routine(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Path(function): Path<String>,
+    Json(args): Json<serde_json::Map<String, serde_json::Value>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    info!("Calling {} for user {}", function, user.sub);
+
+    let not_found = || api_error(StatusCode::NOT_FOUND, format!("Function {} not found", function));
+    let (schema, name) = rpc::parse_name(&function)
+        .filter(|(schema, _)| rpc::is_exposed(state.config.rpc.as_ref(), schema))
+        .ok_or_else(not_found)?;
+
+    let role = mapped_role(&state, &user)?;
+    let mut client = get_client(&state).await?;
+    // Looked up first, so calls to functions that cannot write run read-only
+    let routines = rpc::lookup(&*client, schema, name)
+        .await
+        .map_err(|e| db_error("Function lookup failed", &e))?;
+    if routines.is_empty() {
+        return Err(not_found());
+    }
+    let routine = rpc::select_overload(&routines, &args).map_err(|e| {
+        warn!("Invalid arguments for {}: {}", function, e);
+        api_error(StatusCode::BAD_REQUEST, format!("Cannot call {}: {}", function, e))
+    })?;
+    let (sql, params) = rpc::build_call(routine, &args);
+    enforce_policy(&state, &user, role.as_deref(), &sql)?;
+
+    let read_only = !routine.is_procedure && !routine.is_volatile;
+    let transaction = begin_session(&mut client, role.as_deref(), &user, read_only).await?;
+    let statement = types::prepare(&transaction, &sql)
+        .await
+        .map_err(|e| db_error("Function call failed", &e))?;
+    let params = rpc::bind_args(statement.params(), &params).map_err(|e| {
+        warn!("Invalid arguments for {}: {}", function, e);
+        api_error(StatusCode::BAD_REQUEST, e.to_string())
+    })?;
+    let rows = transaction
+        .query(&statement, &types::param_refs(&params))
+        .await
+        .map_err(|e| db_error("Function call failed", &e))?;
+    transaction
+        .commit()
+        .await
+        .map_err(|e| db_error("Function call failed", &e))?;
+
+    Ok(Json(rpc::result_value(routine, &rows)))
+}
+
+/// Deep enough for the introspection query GraphQL tools send.
+const DEFAULT_GRAPHQL_MAX_DEPTH: usize = 15;
+
+async fn load_catalog(pool: &PostgresPool, schemas: Option<&[String]>) -> Result<Catalog> {
+    let client = pool.get_client().await?;
+    let catalog = Catalog::load(&*client, schemas).await?;
+    info!(
+        "Loaded {} tables and views and {} functions from the database catalog",
+        catalog.len(),
+        catalog.functions().len()
+    );
+    Ok(catalog)
 }
 
 #[tokio::main]
@@ -192,16 +888,128 @@ async fn main() -> Result<()> {
         }
     };
 
+    if config.role_mapping.is_some() {
+        info!("Role mapping enabled - requests run as the role mapped from their claims");
+    }
+
+    let queries = match QueryRegistry::new(config.queries.as_deref().unwrap_or_default()) {
+        Ok(queries) => {
+            let count = config.queries.as_ref().map_or(0, Vec::len);
+            info!("{} named queries loaded", count);
+            Arc::new(queries)
+        }
+        Err(e) => {
+            eprintln!("Failed to load named queries: {}", e);
+            return Err(e);
+        }
+    };
+
+    let locked_down = config.locked_down();
+    let rest_config = config.rest.as_ref();
+    let rest_enabled = !locked_down && rest_config.and_then(|c| c.enabled).unwrap_or(false);
+    let graphql_config = config.graphql.as_ref();
+    let graphql_enabled = !locked_down && graphql_config.and_then(|c| c.enabled).unwrap_or(false);
+    let rpc_config = config.rpc.as_ref();
+    let rpc_enabled = !locked_down && rpc_config.and_then(|c| c.enabled).unwrap_or(false);
+    let catalog = if rest_enabled || graphql_enabled {
+        let schemas = rest_config.and_then(|c| c.schemas.as_deref());
+        match load_catalog(&postgres_pool, schemas).await {
+            Ok(catalog) => Arc::new(catalog),
+            Err(e) => {
+                eprintln!("Failed to load database catalog: {}", e);
+                return Err(e);
+            }
+        }
+    } else {
+        Arc::new(Catalog::default())
+    };
+
+    let graphql = if graphql_enabled {
+        let max_depth = graphql_config
+            .and_then(|c| c.max_depth)
+            .unwrap_or(DEFAULT_GRAPHQL_MAX_DEPTH);
+        let rpc = rpc_config.filter(|_| rpc_enabled);
+        match graphql::build_schema(&catalog, rpc, max_depth) {
+            Ok(Some(schema)) => {
+                info!("GraphQL schema generated");
+                Some(schema)
+            }
+            Ok(None) => {
+                warn!("No tables or functions to expose - GraphQL endpoint disabled");
+                None
+            }
+            Err(e) => {
+                eprintln!("Failed to generate GraphQL schema: {}", e);
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
+
+    let bind_address = config.server.bind_address.clone();
+    let transactions = TransactionRegistry::new(config.transactions.as_ref());
+    let openapi = Arc::new(openapi::document(&config));
+    let listen_enabled = config.listen.is_some();
+    let max_listeners = config
+        .listen
+        .as_ref()
+        .and_then(|c| c.max_connections)
+        .unwrap_or(listen::DEFAULT_MAX_CONNECTIONS);
     let app_state = AppState {
+        config: Arc::new(config),
         postgres_pool,
         oidc_validator,
+        transactions,
+        queries,
+        catalog,
+        graphql,
+        schema_cache: SchemaCache::default(),
+        openapi,
+        listeners: Arc::new(tokio::sync::Semaphore::new(max_listeners)),
     };
 
     // Build the application router
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/health", get(health_check))
-        .route("/query", post(execute_query))
-        .route("/execute", post(execute_mutation))
+        .route("/openapi.json", get(openapi::get_document))
+        .route("/queries/:name", post(run_named_query))
+        .route("/schema", get(introspection::get_schema))
+        .route("/schema/refresh", post(introspection::refresh_schema))
+        .route("/schema/:schema", get(introspection::get_namespace))
+        .route("/schema/:schema/:table", get(introspection::get_table));
+    if rest_enabled {
+        app = app.route(
+            "/tables/:schema/:table",
+            get(rest::read_rows)
+                .post(rest::insert_rows)
+                .patch(rest::update_rows)
+                .delete(rest::delete_rows),
+        );
+    }
+    if app_state.graphql.is_some() {
+        app = app.route("/graphql", post(graphql::execute));
+    }
+    if listen_enabled {
+        app = app.route("/listen/:channel", get(listen::listen));
+    }
+    if locked_down {
+        info!(
+            "Raw SQL disabled - only named queries can be run, /rpc, /tables and /graphql are off"
+        );
+    } else {
+        app = app
+            .route("/query", post(execute_query))
+            .route("/execute", post(execute_mutation))
+            .route("/batch", post(execute_batch))
+            .route("/transactions", post(begin_transaction))
+            .route("/transactions/:id/commit", post(commit_transaction))
+            .route("/transactions/:id/rollback", post(rollback_transaction));
+    }
+    if rpc_enabled {
+        app = app.route("/rpc/:function", post(call_routine));
+    }
+    let app = app
         .layer(middleware::from_fn_with_state(
             app_state.clone(),
             oidc::auth_middleware,
@@ -209,13 +1017,13 @@ async fn main() -> Result<()> {
         .layer(CorsLayer::permissive())
         .with_state(app_state);
 
-    let listener = match tokio::net::TcpListener::bind(&config.server.bind_address).await {
+    let listener = match tokio::net::TcpListener::bind(&bind_address).await {
         Ok(listener) => {
-            info!("Server starting on {}", config.server.bind_address);
+            info!("Server starting on {}", bind_address);
             listener
         }
         Err(e) => {
-            eprintln!("Failed to bind to address {}: {}", config.server.bind_address, e);
+            eprintln!("Failed to bind to address {}: {}", bind_address, e);
             return Err(e.into());
         }
     };
@@ -228,3 +1036,79 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc::Claims;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_status_for_sqlstate() {
+        assert_eq!(status_for_sqlstate(&SqlState::UNIQUE_VIOLATION), StatusCode::CONFLICT);
+        assert_eq!(
+            status_for_sqlstate(&SqlState::IN_FAILED_SQL_TRANSACTION),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(status_for_sqlstate(&SqlState::INSUFFICIENT_PRIVILEGE), StatusCode::FORBIDDEN);
+        assert_eq!(
+            status_for_sqlstate(&SqlState::READ_ONLY_SQL_TRANSACTION),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status_for_sqlstate(&SqlState::QUERY_CANCELED), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(
+            status_for_sqlstate(&SqlState::CONNECTION_FAILURE),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status_for_sqlstate(&SqlState::TOO_MANY_CONNECTIONS),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(status_for_sqlstate(&SqlState::SYNTAX_ERROR), StatusCode::BAD_REQUEST);
+        assert_eq!(status_for_sqlstate(&SqlState::NOT_NULL_VIOLATION), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_error_response_omits_empty_fields() {
+        let body = serde_json::to_value(ErrorResponse::new("Database connection failed")).unwrap();
+        assert_eq!(body, serde_json::json!({"error": "Database connection failed"}));
+    }
+
+    #[test]
+    fn test_error_at_statement() {
+        let error = api_error(StatusCode::BAD_REQUEST, "Invalid value for parameter $1").at_statement(2);
+        assert_eq!(error.0, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            serde_json::to_value(&*error.1).unwrap(),
+            serde_json::json!({
+                "error": "Statement 2 failed: Invalid value for parameter $1",
+                "statement_index": 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_enforce_session_in_pinned_transaction() {
+        // SQL for a pinned transaction must not leave the transaction or its role, even
+        // through set_config, or later requests would run with the changed role
+        let user = AuthenticatedUser(Claims {
+            sub: "user-1".to_string(),
+            iss: "https://issuer".to_string(),
+            aud: None,
+            exp: 0,
+            iat: 0,
+            other: HashMap::new(),
+        });
+        for sql in [
+            "COMMIT",
+            "SET ROLE postgres",
+            "SELECT set_config('role', 'postgres', false)",
+            "SELECT pg_catalog.set_config('session_authorization', 'postgres', false)",
+            "UPDATE t SET x = 1 WHERE set_config('role', 'postgres', true) IS NOT NULL",
+        ] {
+            let error = enforce_session(&user, sql).expect_err(sql);
+            assert_eq!(error.0, StatusCode::BAD_REQUEST);
+        }
+        assert!(enforce_session(&user, "SELECT set_config('search_path', 'app', true)").is_ok());
+    }
+}
@@ -1,13 +1,15 @@
 use anyhow::{anyhow, Result};
 use axum::{
-    extract::{Request, State},
-    http::{HeaderMap, StatusCode},
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, HeaderMap, StatusCode},
     middleware::Next,
     response::Response,
 };
 use jsonwebtoken::{decode, decode_header, DecodingKey, Validation, Algorithm};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
@@ -15,7 +17,7 @@ use tracing::{info, warn};
 
 use crate::config::OidcConfig;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub iss: String,
@@ -50,10 +52,25 @@ struct CachedJwks {
     cached_at: Instant,
 }
 
+/// The subset of the OpenID Provider Metadata document that the validator uses.
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    jwks_uri: String,
+    #[serde(default)]
+    id_token_signing_alg_values_supported: Vec<String>,
+}
+
+struct CachedMetadata {
+    metadata: ProviderMetadata,
+    cached_at: Instant,
+}
+
 pub struct OidcValidator {
     config: OidcConfig,
     client: reqwest::Client,
     jwks_cache: Arc<RwLock<Option<CachedJwks>>>,
+    metadata: Arc<RwLock<Option<CachedMetadata>>>,
 }
 
 impl OidcValidator {
@@ -62,11 +79,16 @@ impl OidcValidator {
         eprintln!("Creating OIDC validator...");
         
         let client = reqwest::Client::new();
+
+        if let Some(allowed) = &config.allowed_algorithms {
+            parse_algorithms(allowed)?;
+        }
         
         let validator = Self {
             config: config.clone(),
             client,
             jwks_cache: Arc::new(RwLock::new(None)),
+            metadata: Arc::new(RwLock::new(None)),
         };
 
         // Skip JWKS loading if validation is disabled (for development)
@@ -87,8 +109,81 @@ impl OidcValidator {
         Ok(validator)
     }
 
+    /// Fetches the discovery document, cached for as long as the JWKS so that a changed
+    /// `jwks_uri` or algorithm list is picked up when the keys are refreshed.
+    async fn discover(&self) -> Result<ProviderMetadata> {
+        {
+            let cache = self.metadata.read().await;
+            if let Some(cached) = cache.as_ref() {
+                if self.is_fresh(cached.cached_at) {
+                    return Ok(cached.metadata.clone());
+                }
+            }
+        }
+
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer_url.trim_end_matches('/')
+        );
+        let response = self.client.get(&discovery_url).send().await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to fetch OIDC discovery document: HTTP {}", response.status()));
+        }
+
+        let metadata: ProviderMetadata = response.json().await?;
+
+        // The discovery document must describe exactly the issuer we were configured with
+        // (OpenID Connect Discovery 1.0, section 4.3)
+        if metadata.issuer != self.config.issuer_url {
+            return Err(anyhow!(
+                "OIDC discovery issuer mismatch: expected {}, got {}",
+                self.config.issuer_url,
+                metadata.issuer
+            ));
+        }
+
+        info!("Discovered OIDC provider metadata, jwks_uri: {}", metadata.jwks_uri);
+        *self.metadata.write().await = Some(CachedMetadata {
+            metadata: metadata.clone(),
+            cached_at: Instant::now(),
+        });
+
+        Ok(metadata)
+    }
+
+    async fn jwks_uri(&self) -> Result<String> {
+        match &self.config.jwks_uri {
+            Some(jwks_uri) => Ok(jwks_uri.clone()),
+            None => Ok(self.discover().await?.jwks_uri),
+        }
+    }
+
+    /// Algorithms accepted for JWKS-verified tokens: the configured allowlist,
+    /// else the algorithms advertised by the provider, else every asymmetric algorithm.
+    async fn allowed_algorithms(&self) -> Result<Vec<Algorithm>> {
+        if let Some(allowed) = &self.config.allowed_algorithms {
+            return parse_algorithms(allowed);
+        }
+
+        if self.config.jwks_uri.is_none() {
+            let metadata = self.discover().await?;
+            let advertised: Vec<Algorithm> = metadata
+                .id_token_signing_alg_values_supported
+                .iter()
+                .filter_map(|alg| Algorithm::from_str(alg).ok())
+                .filter(|alg| ASYMMETRIC_ALGORITHMS.contains(alg))
+                .collect();
+            if !advertised.is_empty() {
+                return Ok(advertised);
+            }
+        }
+
+        Ok(ASYMMETRIC_ALGORITHMS.to_vec())
+    }
+
     async fn fetch_jwks(&self) -> Result<Jwks> {
-        let jwks_url = format!("{}/.well-known/jwks.json", self.config.issuer_url);
+        let jwks_url = self.jwks_uri().await?;
         let response = self.client.get(&jwks_url).send().await?;
         
         if !response.status().is_success() {
@@ -113,8 +208,7 @@ impl OidcValidator {
         {
             let cache = self.jwks_cache.read().await;
             if let Some(cached) = cache.as_ref() {
-                let cache_age = cached.cached_at.elapsed();
-                if cache_age < Duration::from_secs(self.config.jwks_cache_duration_seconds) {
+                if self.is_fresh(cached.cached_at) {
                     return Ok(cached.jwks.clone());
                 }
             }
@@ -124,11 +218,17 @@ impl OidcValidator {
         self.fetch_jwks().await
     }
 
-    fn find_key<'a>(&self, jwks: &'a Jwks, kid: Option<&str>) -> Option<&'a JwksKey> {
+    fn is_fresh(&self, cached_at: Instant) -> bool {
+        cached_at.elapsed() < Duration::from_secs(self.config.jwks_cache_duration_seconds)
+    }
+
+    fn find_key<'a>(&self, jwks: &'a Jwks, kid: Option<&str>, alg: Algorithm) -> Option<&'a JwksKey> {
         if let Some(kid) = kid {
-            jwks.keys.iter().find(|key| key.kid.as_deref() == Some(kid))
+            jwks.keys
+                .iter()
+                .find(|key| key.kid.as_deref() == Some(kid) && key_supports_algorithm(key, alg))
         } else {
-            jwks.keys.first()
+            jwks.keys.iter().find(|key| key_supports_algorithm(key, alg))
         }
     }
 
@@ -146,6 +246,11 @@ impl OidcValidator {
                 
                 DecodingKey::from_ec_components(x, y).map_err(|e| anyhow!("Failed to create EC key: {}", e))
             }
+            "OKP" => {
+                let x = key.x.as_ref().ok_or_else(|| anyhow!("Missing 'x' parameter for OKP key"))?;
+
+                DecodingKey::from_ed_components(x).map_err(|e| anyhow!("Failed to create OKP key: {}", e))
+            }
             _ => Err(anyhow!("Unsupported key type: {}", key.kty)),
         }
     }
@@ -154,14 +259,7 @@ impl OidcValidator {
         // Skip validation if disabled (for development)
         if self.config.skip_validation.unwrap_or(false) {
             info!("Token validation skipped - development mode");
-            return Ok(Claims {
-                sub: "dev-user".to_string(),
-                iss: "dev-issuer".to_string(),
-                aud: None,
-                exp: 9999999999, // Far future
-                iat: 1000000000,
-                other: HashMap::new(),
-            });
+            return Ok(development_claims());
         }
 
         // Check if we have a development secret for HS256 validation
@@ -187,18 +285,23 @@ impl OidcValidator {
         let header = decode_header(token)?;
         let kid = header.kid.as_deref();
 
+        let allowed = self.allowed_algorithms().await?;
+        if !allowed.contains(&header.alg) {
+            return Err(anyhow!("Token algorithm {:?} is not allowed", header.alg));
+        }
+
         // Get JWKS
         let jwks = self.get_jwks().await?;
 
         // Find the appropriate key
-        let key = self.find_key(&jwks, kid)
+        let key = self.find_key(&jwks, kid, header.alg)
             .ok_or_else(|| anyhow!("No matching key found for token"))?;
 
         // Create decoding key
         let decoding_key = self.create_decoding_key(key)?;
 
         // Set up validation
-        let mut validation = Validation::new(Algorithm::RS256);
+        let mut validation = Validation::new(header.alg);
         validation.set_issuer(&[&self.config.issuer_url]);
         
         if let Some(audience) = &self.config.audience {
@@ -214,20 +317,122 @@ impl OidcValidator {
     }
 }
 
+/// Claims used for every request when validation is disabled (for development).
+fn development_claims() -> Claims {
+    Claims {
+        sub: "dev-user".to_string(),
+        iss: "dev-issuer".to_string(),
+        aud: None,
+        exp: 9999999999, // Far future
+        iat: 1000000000,
+        other: HashMap::new(),
+    }
+}
+
+/// Extractor for the claims of the caller, validated by `auth_middleware`.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser(pub Claims);
+
+impl std::ops::Deref for AuthenticatedUser {
+    type Target = Claims;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthenticatedUser
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Claims>()
+            .cloned()
+            .map(AuthenticatedUser)
+            .ok_or_else(|| {
+                warn!("No authenticated user for {}", parts.uri.path());
+                StatusCode::UNAUTHORIZED
+            })
+    }
+}
+
+/// Algorithms that can be verified with keys published in a JWKS.
+const ASYMMETRIC_ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+fn parse_algorithms(names: &[String]) -> Result<Vec<Algorithm>> {
+    names
+        .iter()
+        .map(|name| {
+            let alg = Algorithm::from_str(name).map_err(|_| anyhow!("Unknown algorithm: {}", name))?;
+            if !ASYMMETRIC_ALGORITHMS.contains(&alg) {
+                return Err(anyhow!("Algorithm {} cannot be used with JWKS keys", name));
+            }
+            Ok(alg)
+        })
+        .collect()
+}
+
+/// Checks that a JWK can verify signatures made with `alg`, based on its `alg`, `kty` and `crv`.
+fn key_supports_algorithm(key: &JwksKey, alg: Algorithm) -> bool {
+    if let Some(key_alg) = &key.alg {
+        if Algorithm::from_str(key_alg).ok() != Some(alg) {
+            return false;
+        }
+    }
+
+    let crv = key.crv.as_deref();
+    match alg {
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => key.kty == "RSA",
+        Algorithm::ES256 => key.kty == "EC" && crv == Some("P-256"),
+        Algorithm::ES384 => key.kty == "EC" && crv == Some("P-384"),
+        Algorithm::EdDSA => key.kty == "OKP" && crv == Some("Ed25519"),
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => false,
+    }
+}
+
 pub async fn auth_middleware(
     State(state): State<crate::AppState>,
     headers: HeaderMap,
-    request: Request,
+    mut request: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    // Skip authentication for health check
-    if request.uri().path() == "/health" {
-        return Ok(next.run(request).await);
+    // Skip authentication for the health check, and the API description if it is public
+    let public_openapi = state
+        .config
+        .openapi
+        .as_ref()
+        .and_then(|c| c.public)
+        .unwrap_or(false);
+    match request.uri().path() {
+        "/health" => return Ok(next.run(request).await),
+        "/openapi.json" if public_openapi => return Ok(next.run(request).await),
+        _ => {}
     }
 
     // Skip authentication if validation is disabled (for development)
     if state.oidc_validator.config.skip_validation.unwrap_or(false) {
         info!("Authentication skipped - development mode");
+        request.extensions_mut().insert(development_claims());
         return Ok(next.run(request).await);
     }
 
@@ -252,7 +457,7 @@ pub async fn auth_middleware(
     match state.oidc_validator.validate_token(token).await {
         Ok(claims) => {
             info!("User authenticated: {}", claims.sub);
-            // Add user info to request extensions if needed
+            request.extensions_mut().insert(claims);
             Ok(next.run(request).await)
         }
         Err(e) => {
@@ -261,3 +466,43 @@ pub async fn auth_middleware(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(kty: &str, crv: Option<&str>, alg: Option<&str>) -> JwksKey {
+        JwksKey {
+            kty: kty.to_string(),
+            key_use: Some("sig".to_string()),
+            kid: None,
+            n: None,
+            e: None,
+            x: None,
+            y: None,
+            crv: crv.map(str::to_string),
+            alg: alg.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_key_supports_algorithm() {
+        assert!(key_supports_algorithm(&key("RSA", None, None), Algorithm::PS256));
+        assert!(key_supports_algorithm(&key("RSA", None, Some("RS256")), Algorithm::RS256));
+        assert!(!key_supports_algorithm(&key("RSA", None, Some("RS256")), Algorithm::PS256));
+        assert!(key_supports_algorithm(&key("EC", Some("P-256"), None), Algorithm::ES256));
+        assert!(!key_supports_algorithm(&key("EC", Some("P-256"), None), Algorithm::ES384));
+        assert!(key_supports_algorithm(&key("OKP", Some("Ed25519"), None), Algorithm::EdDSA));
+        assert!(!key_supports_algorithm(&key("RSA", None, None), Algorithm::HS256));
+    }
+
+    #[test]
+    fn test_parse_algorithms_rejects_symmetric() {
+        assert_eq!(
+            parse_algorithms(&["ES256".to_string(), "EdDSA".to_string()]).unwrap(),
+            vec![Algorithm::ES256, Algorithm::EdDSA]
+        );
+        assert!(parse_algorithms(&["HS256".to_string()]).is_err());
+        assert!(parse_algorithms(&["none".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use utoipa::openapi::path::{HttpMethod, OperationBuilder, ParameterBuilder, ParameterIn};
+use utoipa::openapi::request_body::RequestBodyBuilder;
+use utoipa::openapi::schema::{
+    AdditionalProperties, KnownFormat, ObjectBuilder, SchemaFormat, SchemaType, Type,
+};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme};
+use utoipa::openapi::{
+    ComponentsBuilder, Content, InfoBuilder, OpenApi, OpenApiBuilder, PathItem, PathsBuilder, Ref,
+    RefOr, Required, ResponseBuilder, Schema,
+};
+use utoipa::ToSchema;
+
+use crate::columnar::{ARROW_STREAM_CONTENT_TYPE, PARQUET_CONTENT_TYPE};
+use crate::config::{Config, NamedQuery, ParamType};
+use crate::format::{CSV_CONTENT_TYPE, NDJSON_CONTENT_TYPE, TSV_CONTENT_TYPE};
+use crate::transactions::TRANSACTION_ID_HEADER;
+use crate::{AppState, ErrorResponse, QueryRequest, QueryResponse};
+
+const SECURITY_SCHEME: &str = "bearer";
+
+/// Serves the OpenAPI document built at startup.
+pub async fn get_document(State(state): State<AppState>) -> Response {
+    Json(&*state.openapi).into_response()
+}
+
+/// Describes `/health`, the raw SQL endpoints if enabled, and every named query.
+pub fn document(config: &Config) -> OpenApi {
+    let mut paths = PathsBuilder::new().path("/health", PathItem::new(HttpMethod::Get, health()));
+    if !config.locked_down() {
+        paths = paths
+            .path("/query", PathItem::new(HttpMethod::Post, query()))
+            .path("/execute", PathItem::new(HttpMethod::Post, execute()));
+    }
+    for named_query in config.queries.iter().flatten() {
+        paths = paths.path(
+            format!("/queries/{}", named_query.name),
+            PathItem::new(HttpMethod::Post, run_named_query(named_query)),
+        );
+    }
+
+    let bearer = HttpBuilder::new()
+        .scheme(HttpAuthScheme::Bearer)
+        .bearer_format("JWT")
+        .description(Some(format!(
+            "A JWT issued by {} for client {}",
+            config.oidc.issuer_url, config.oidc.client_id
+        )))
+        .build();
+    let components = ComponentsBuilder::new()
+        .schemas_from_iter(schemas::<QueryRequest>())
+        .schemas_from_iter(schemas::<QueryResponse>())
+        .schemas_from_iter(schemas::<ErrorResponse>())
+        .security_scheme(SECURITY_SCHEME, SecurityScheme::Http(bearer))
+        .build();
+
+    OpenApiBuilder::new()
+        .info(
+            InfoBuilder::new()
+                .title("PostgreSQL OIDC Proxy")
+                .version(env!("CARGO_PKG_VERSION"))
+                .build(),
+        )
+        .paths(paths)
+        .components(Some(components))
+        .security(Some([SecurityRequirement::new(
+            SECURITY_SCHEME,
+            Vec::<String>::new(),
+        )]))
+        .build()
+}
+
+/// The schema of `T` and of the types it refers to.
+fn schemas<T: ToSchema>() -> Vec<(String, RefOr<Schema>)> {
+    let mut schemas = vec![(T::name().to_string(), T::schema())];
+    T::schemas(&mut schemas);
+    schemas
+}
+
+fn health() -> OperationBuilder {
+    let status = ObjectBuilder::new()
+        .property("status", ObjectBuilder::new().schema_type(Type::String))
+        .property("service", ObjectBuilder::new().schema_type(Type::String))
+        .required("status")
+        .required("service");
+    OperationBuilder::new()
+        .operation_id(Some("health"))
+        .summary(Some("Health check"))
+        .securities(Some(Vec::new()))
+        .response(
+            "200",
+            ResponseBuilder::new()
+                .description("The service is running")
+                .content("application/json", Content::new(Some(status))),
+        )
+}
+
+fn query() -> OperationBuilder {
+    let json = Content::new(Some(Ref::from_schema_name("QueryResponse")));
+    let text = || Content::new(Some(ObjectBuilder::new().schema_type(Type::String)));
+    let binary = || {
+        Content::new(Some(
+            ObjectBuilder::new()
+                .schema_type(Type::String)
+                .content_media_type("application/octet-stream"),
+        ))
+    };
+    let rows = ResponseBuilder::new()
+        .description("Result rows, in the format chosen by `format` or the Accept header")
+        .content("application/json", json)
+        .content(NDJSON_CONTENT_TYPE, text())
+        .content(CSV_CONTENT_TYPE, text())
+        .content(TSV_CONTENT_TYPE, text())
+        .content(ARROW_STREAM_CONTENT_TYPE, binary())
+        .content(PARQUET_CONTENT_TYPE, binary());
+
+    sql_operation("query", "Run a read-only query").response("200", rows)
+}
+
+fn execute() -> OperationBuilder {
+    sql_operation("execute", "Run a statement that modifies data")
+        .response("200", query_response("The rows returned or affected"))
+}
+
+/// `/query` and `/execute` take the same request and can join an interactive transaction.
+fn sql_operation(operation_id: &str, summary: &str) -> OperationBuilder {
+    let transaction_id = ParameterBuilder::new()
+        .name(TRANSACTION_ID_HEADER)
+        .parameter_in(ParameterIn::Header)
+        .required(Required::False)
+        .description(Some("Runs the statement in this interactive transaction"))
+        .schema(Some(
+            ObjectBuilder::new()
+                .schema_type(Type::String)
+                .format(Some(SchemaFormat::KnownFormat(KnownFormat::Uuid))),
+        ));
+    let body = RequestBodyBuilder::new()
+        .required(Some(Required::True))
+        .content(
+            "application/json",
+            Content::new(Some(Ref::from_schema_name("QueryRequest"))),
+        );
+
+    with_errors(
+        OperationBuilder::new()
+            .operation_id(Some(operation_id))
+            .summary(Some(summary))
+            .parameter(transaction_id)
+            .request_body(Some(body.build()))
+            .response(
+                "409",
+                error_response("A constraint was violated or the transaction conflicted"),
+            ),
+    )
+}
+
+fn run_named_query(named_query: &NamedQuery) -> OperationBuilder {
+    let mut arguments = ObjectBuilder::new()
+        .schema_type(Type::Object)
+        .additional_properties(Some(AdditionalProperties::FreeForm(false)));
+    for param in named_query.params.iter().flatten() {
+        let required = param.required.unwrap_or(param.default.is_none());
+        let schema_type = if required {
+            SchemaType::new(value_type(param.param_type))
+        } else {
+            SchemaType::from_iter([value_type(param.param_type), Type::Null])
+        };
+        let property = ObjectBuilder::new()
+            .schema_type(schema_type)
+            .default(param.default.clone());
+        arguments = arguments.property(&param.name, property);
+        if required {
+            arguments = arguments.required(&param.name);
+        }
+    }
+
+    // The document can be made public with `openapi.public`, so it leaves out the role
+    // and claim a query requires
+    let description =
+        (!named_query.read_only.unwrap_or(true)).then_some("Runs in a read-write transaction");
+    let body = RequestBodyBuilder::new()
+        .required(Some(Required::True))
+        .content("application/json", Content::new(Some(arguments)));
+    with_errors(
+        OperationBuilder::new()
+            .operation_id(Some(format!("query_{}", named_query.name)))
+            .summary(Some(format!("Run the named query {}", named_query.name)))
+            .description(description)
+            .request_body(Some(body.build()))
+            .response("200", query_response("The rows returned or affected")),
+    )
+}
+
+/// Adds the error responses every authenticated endpoint can return.
+fn with_errors(operation: OperationBuilder) -> OperationBuilder {
+    operation
+        .response("400", error_response("Invalid request or SQL error"))
+        .response(
+            "401",
+            ResponseBuilder::new().description("Missing or invalid bearer token"),
+        )
+        .response(
+            "403",
+            error_response("Not allowed for the caller's role, claims or SQL policy"),
+        )
+        .response("503", error_response("The database is unavailable"))
+}
+
+fn query_response(description: &str) -> ResponseBuilder {
+    ResponseBuilder::new().description(description).content(
+        "application/json",
+        Content::new(Some(Ref::from_schema_name("QueryResponse"))),
+    )
+}
+
+fn error_response(description: &str) -> ResponseBuilder {
+    ResponseBuilder::new().description(description).content(
+        "application/json",
+        Content::new(Some(Ref::from_schema_name("ErrorResponse"))),
+    )
+}
+
+fn value_type(param_type: ParamType) -> Type {
+    match param_type {
+        ParamType::String => Type::String,
+        ParamType::Integer => Type::Integer,
+        ParamType::Number => Type::Number,
+        ParamType::Boolean => Type::Boolean,
+        ParamType::Array => Type::Array,
+        ParamType::Object => Type::Object,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QueryParam;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_document() {
+        let mut config = Config::default();
+        config.server.raw_sql = Some(false);
+        config.queries = Some(vec![NamedQuery {
+            name: "orders_by_customer".to_string(),
+            sql: "SELECT * FROM orders WHERE customer_id = $1 LIMIT $2".to_string(),
+            params: Some(vec![
+                QueryParam {
+                    name: "customer_id".to_string(),
+                    param_type: ParamType::Integer,
+                    required: None,
+                    default: None,
+                },
+                QueryParam {
+                    name: "limit".to_string(),
+                    param_type: ParamType::Integer,
+                    required: None,
+                    default: Some(json!(10)),
+                },
+            ]),
+            role: Some("analyst".to_string()),
+            claim: None,
+            value: None,
+            read_only: None,
+        }]);
+
+        let document: Value = serde_json::to_value(document(&config)).unwrap();
+        let paths = document["paths"].as_object().unwrap();
+        assert_eq!(
+            paths.keys().collect::<Vec<_>>(),
+            ["/health", "/queries/orders_by_customer"]
+        );
+        assert_eq!(paths["/health"]["get"]["security"], json!([]));
+
+        let body = &paths["/queries/orders_by_customer"]["post"]["requestBody"]["content"]
+            ["application/json"]["schema"];
+        assert_eq!(body["required"], json!(["customer_id"]));
+        assert_eq!(
+            body["properties"]["limit"]["type"],
+            json!(["integer", "null"])
+        );
+        assert_eq!(body["properties"]["limit"]["default"], json!(10));
+
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        for name in [
+            "QueryRequest",
+            "QueryResponse",
+            "ErrorResponse",
+            "ColumnInfo",
+        ] {
+            assert!(schemas.contains_key(name), "missing schema {}", name);
+        }
+        assert_eq!(
+            document["components"]["securitySchemes"]["bearer"]["scheme"],
+            "bearer"
+        );
+    }
+}
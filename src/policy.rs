@@ -0,0 +1,727 @@
+use serde::Deserialize;
+use sqlparser::ast::{
+    visit_expressions, Expr, Function, FunctionArg, FunctionArgExpr, FunctionArguments, ObjectName,
+    OneOrManyWithParens, Query, SetExpr, Statement, Value, Visit, Visitor,
+};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::ControlFlow;
+
+use crate::config::{PolicyRule, SqlPolicyConfig};
+use crate::oidc::Claims;
+use crate::session;
+
+/// Broad kind of an SQL statement, as used in policy rules.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementClass {
+    /// Queries that only read, including `EXPLAIN`
+    Select,
+    /// `SET`, `SHOW`, transaction control and other commands
+    Utility,
+    /// `INSERT`, `UPDATE`, `DELETE`, `MERGE`, `TRUNCATE`, `COPY` and `CALL`
+    Dml,
+    /// `CREATE`, `ALTER`, `DROP`, `COMMENT`, `GRANT`, `REVOKE` and `SELECT INTO`
+    Ddl,
+    /// More than one statement in a single request
+    Multi,
+}
+
+impl fmt::Display for StatementClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            StatementClass::Select => "SELECT",
+            StatementClass::Utility => "utility",
+            StatementClass::Dml => "DML",
+            StatementClass::Ddl => "DDL",
+            StatementClass::Multi => "multi-statement",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What a request's SQL does, as far as policies are concerned.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Classification {
+    /// Class of each statement, in order
+    pub statements: Vec<StatementClass>,
+    /// Schema-qualified names of the tables the SQL refers to
+    pub tables: Vec<(String, String)>,
+    /// Schema and name of the schema-qualified functions the SQL calls
+    pub functions: Vec<(String, String)>,
+    /// Whether the SQL changes `search_path`, which decides where unqualified names
+    /// resolve
+    pub sets_search_path: bool,
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    /// The SQL could not be parsed, so no rule can be checked
+    Parse(String),
+    /// A rule rejected the SQL
+    Blocked { rule: String, reason: String },
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::Parse(e) => write!(f, "Failed to parse SQL: {}", e),
+            PolicyError::Blocked { rule, reason } => {
+                write!(f, "Blocked by SQL policy rule '{}': {}", rule, reason)
+            }
+        }
+    }
+}
+
+/// Checks `sql` against the rules that apply to the caller.
+///
+/// SQL is only parsed when at least one rule applies. If it cannot be parsed, it is
+/// rejected rather than sent upstream unchecked.
+pub fn check(
+    config: Option<&SqlPolicyConfig>,
+    role: Option<&str>,
+    claims: &Claims,
+    sql: &str,
+) -> Result<(), PolicyError> {
+    let rules: Vec<&PolicyRule> = config
+        .map(|config| {
+            config
+                .rules
+                .iter()
+                .filter(|rule| rule_applies(rule, role, claims))
+                .collect()
+        })
+        .unwrap_or_default();
+    if rules.is_empty() {
+        return Ok(());
+    }
+
+    let classification = classify(sql)?;
+    for rule in rules {
+        if let Some(reason) = violation(rule, &classification) {
+            return Err(PolicyError::Blocked {
+                rule: rule.name.clone(),
+                reason,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn rule_applies(rule: &PolicyRule, role: Option<&str>, claims: &Claims) -> bool {
+    let role_matches = rule
+        .role
+        .as_deref()
+        .is_none_or(|expected| role == Some(expected));
+    let claim_matches = match (&rule.claim, &rule.value) {
+        (Some(claim), Some(value)) => session::claim_matches(claims, claim, value),
+        (Some(_), None) | (None, Some(_)) => false,
+        (None, None) => true,
+    };
+    role_matches && claim_matches
+}
+
+/// Returns why `rule` rejects the classified SQL, if it does.
+fn violation(rule: &PolicyRule, classification: &Classification) -> Option<String> {
+    let mut classes: Vec<StatementClass> = classification.statements.clone();
+    if classes.len() > 1 {
+        classes.push(StatementClass::Multi);
+    }
+
+    if let Some(allow) = &rule.allow {
+        if let Some(class) = classes.iter().find(|class| !allow.contains(class)) {
+            return Some(not_allowed(*class));
+        }
+    }
+    if let Some(deny) = &rule.deny {
+        if let Some(class) = classes.iter().find(|class| deny.contains(class)) {
+            return Some(not_allowed(*class));
+        }
+    }
+    if let Some(schemas) = &rule.schemas {
+        // Unqualified names are checked as `public`, which only holds for the default
+        // search path
+        if classification.sets_search_path {
+            return Some("changing search_path is not allowed".to_string());
+        }
+        if let Some((schema, table)) = classification
+            .tables
+            .iter()
+            .find(|(schema, _)| !schemas.contains(schema))
+        {
+            return Some(format!(
+                "table {}.{} is outside the allowed schemas",
+                schema, table
+            ));
+        }
+        if let Some((schema, function)) = classification
+            .functions
+            .iter()
+            .find(|(schema, _)| !schemas.contains(schema))
+        {
+            return Some(format!(
+                "function {}.{} is outside the allowed schemas",
+                schema, function
+            ));
+        }
+    }
+    None
+}
+
+fn not_allowed(class: StatementClass) -> String {
+    match class {
+        StatementClass::Multi => "multiple statements per request are not allowed".to_string(),
+        class => format!("{} statements are not allowed", class),
+    }
+}
+
+/// Parses `sql` with the PostgreSQL dialect and classifies each statement.
+pub fn classify(sql: &str) -> Result<Classification, PolicyError> {
+    let statements = Parser::parse_sql(&PostgreSqlDialect {}, sql)
+        .map_err(|e| PolicyError::Parse(e.to_string()))?;
+
+    let mut classes = Vec::with_capacity(statements.len());
+    let mut tables = Vec::new();
+    let mut functions = Vec::new();
+    let mut sets_search_path = false;
+    for statement in &statements {
+        let mut analyzer = Analyzer::default();
+        let _ = statement.visit(&mut analyzer);
+        classes.push(analyzer.class);
+        functions.extend(analyzer.functions);
+        sets_search_path |= analyzer.sets_search_path;
+
+        for name in analyzer.relations {
+            // Unqualified references to a CTE are not tables
+            if name.0.len() == 1 && analyzer.ctes.contains(&ident_name(&name.0[0])) {
+                continue;
+            }
+            tables.push(qualified_name(&name));
+        }
+    }
+
+    Ok(Classification {
+        statements: classes,
+        tables,
+        functions,
+        sets_search_path,
+    })
+}
+
+/// Returns why `sql` cannot run in a transaction the proxy opened for the caller, if it
+/// cannot: ending the transaction or changing its role, directly or through
+/// `set_config`, would escape the caller's session.
+///
+/// This is checked whether or not any policy rule applies.
+pub fn session_violation(sql: &str) -> Option<String> {
+    let kind = match Parser::parse_sql(&PostgreSqlDialect {}, sql) {
+        Ok(statements) => statements
+            .iter()
+            .find_map(session_statement)
+            .or_else(|| set_config_violation(&statements)),
+        // The parser does not know RESET, SET SESSION AUTHORIZATION or PREPARE TRANSACTION
+        Err(_) => leading_words(sql)
+            .iter()
+            .find_map(|words| session_words(words))
+            .or_else(|| set_config_tokens(sql)),
+    }?;
+    Some(format!("{} are not allowed", kind))
+}
+
+fn session_statement(statement: &Statement) -> Option<&'static str> {
+    match statement {
+        Statement::StartTransaction { .. }
+        | Statement::Commit { .. }
+        | Statement::Rollback { .. }
+        | Statement::Savepoint { .. }
+        | Statement::ReleaseSavepoint { .. } => Some("transaction control statements"),
+        Statement::SetRole { .. } => Some("role changes"),
+        // SET role = ..., SET session_authorization = ... and SET request.jwt.claims = ...
+        Statement::SetVariable { variables, .. } => {
+            let names: &[ObjectName] = match variables {
+                OneOrManyWithParens::One(name) => std::slice::from_ref(name),
+                OneOrManyWithParens::Many(names) => names,
+            };
+            names.iter().find_map(|name| {
+                let parts: Vec<String> = name.0.iter().map(ident_name).collect();
+                setting_kind(&parts.join("."))
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Checks the `set_config` calls anywhere in `statements`, e.g. in a CTE or a WHERE clause.
+fn set_config_violation(statements: &Vec<Statement>) -> Option<&'static str> {
+    let found = visit_expressions(statements, |expr| {
+        let Expr::Function(function) = expr else {
+            return ControlFlow::Continue(());
+        };
+        let Some(setting) = set_config_setting(function) else {
+            return ControlFlow::Continue(());
+        };
+        match setting.map_or(Some(COMPUTED_SETTING), setting_kind) {
+            Some(kind) => ControlFlow::Break(kind),
+            None => ControlFlow::Continue(()),
+        }
+    });
+    match found {
+        ControlFlow::Break(kind) => Some(kind),
+        ControlFlow::Continue(()) => None,
+    }
+}
+
+/// The same check for SQL the parser rejects: every `set_config(` followed by its first
+/// argument.
+fn set_config_tokens(sql: &str) -> Option<&'static str> {
+    let Ok(tokens) = Tokenizer::new(&PostgreSqlDialect {}, sql).tokenize() else {
+        return None;
+    };
+    let tokens: Vec<&Token> = tokens
+        .iter()
+        .filter(|token| !matches!(token, Token::Whitespace(_)))
+        .collect();
+    tokens.windows(4).find_map(|window| match window {
+        [Token::Word(word), Token::LParen, argument, next]
+            if word.value.eq_ignore_ascii_case("set_config") =>
+        {
+            let setting = match argument {
+                Token::SingleQuotedString(s) | Token::EscapedStringLiteral(s) => Some(s.as_str()),
+                Token::DollarQuotedString(s) => Some(s.value.as_str()),
+                _ => None,
+            };
+            match setting {
+                Some(setting) if **next == Token::Comma => setting_kind(setting),
+                _ => Some(COMPUTED_SETTING),
+            }
+        }
+        _ => None,
+    })
+}
+
+const COMPUTED_SETTING: &str = "set_config calls with a computed setting name";
+
+/// For a `set_config` call, the setting it changes, or `Some(None)` if the name is not
+/// a string literal.
+fn set_config_setting(function: &Function) -> Option<Option<&str>> {
+    let is_set_config = match function.name.0.as_slice() {
+        [name] => ident_name(name) == "set_config",
+        [schema, name] => ident_name(schema) == "pg_catalog" && ident_name(name) == "set_config",
+        _ => false,
+    };
+    if !is_set_config {
+        return None;
+    }
+    let setting = match &function.args {
+        FunctionArguments::List(list) => match list.args.first() {
+            Some(FunctionArg::Unnamed(FunctionArgExpr::Expr(Expr::Value(value)))) => {
+                string_value(value)
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    Some(setting)
+}
+
+fn string_value(value: &Value) -> Option<&str> {
+    match value {
+        Value::SingleQuotedString(s) | Value::EscapedStringLiteral(s) => Some(s),
+        Value::DollarQuotedString(s) => Some(&s.value),
+        _ => None,
+    }
+}
+
+/// Settings SQL must not change: the role, and the claims the proxy sets for the caller.
+fn setting_kind(setting: &str) -> Option<&'static str> {
+    if is_role_setting(setting) {
+        Some("role changes")
+    } else if setting.to_lowercase().starts_with("request.jwt.") {
+        Some("changes to request.jwt settings")
+    } else {
+        None
+    }
+}
+
+fn session_words(words: &[String]) -> Option<&'static str> {
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    match words.as_slice() {
+        ["begin" | "start" | "commit" | "end" | "rollback" | "abort" | "savepoint" | "release", ..]
+        | ["prepare", "transaction", ..] => Some("transaction control statements"),
+        ["set" | "reset", "session" | "local", "session", ..]
+        | ["set" | "reset", "session", "authorization", ..] => Some("role changes"),
+        ["set" | "reset", "session" | "local", setting, ..] | ["set" | "reset", setting, ..]
+            if setting_kind(setting).is_some() =>
+        {
+            setting_kind(setting)
+        }
+        _ => None,
+    }
+}
+
+fn is_role_setting(name: &str) -> bool {
+    name.eq_ignore_ascii_case("role") || name.eq_ignore_ascii_case("session_authorization")
+}
+
+/// The leading keywords of each statement in `sql`, up to three and in lower case.
+/// Dotted names such as `request.jwt.claims` count as one word.
+fn leading_words(sql: &str) -> Vec<Vec<String>> {
+    let Ok(tokens) = Tokenizer::new(&PostgreSqlDialect {}, sql).tokenize() else {
+        return Vec::new();
+    };
+    tokens
+        .split(|token| *token == Token::SemiColon)
+        .map(|statement| {
+            let mut words: Vec<String> = Vec::new();
+            let mut dotted = false;
+            for token in statement {
+                match token {
+                    Token::Whitespace(_) => {}
+                    Token::Period if !dotted && !words.is_empty() => dotted = true,
+                    Token::Word(word) if dotted => {
+                        let last = words.last_mut().expect("a word precedes the period");
+                        last.push('.');
+                        last.push_str(&word.value.to_lowercase());
+                        dotted = false;
+                    }
+                    Token::Word(word) if words.len() < 3 => words.push(word.value.to_lowercase()),
+                    _ => break,
+                }
+            }
+            words
+        })
+        .collect()
+}
+
+/// Walks one statement, including nested ones such as data-modifying CTEs.
+struct Analyzer {
+    class: StatementClass,
+    relations: Vec<ObjectName>,
+    ctes: HashSet<String>,
+    functions: Vec<(String, String)>,
+    sets_search_path: bool,
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Self {
+            class: StatementClass::Select,
+            relations: Vec::new(),
+            ctes: HashSet::new(),
+            functions: Vec::new(),
+            sets_search_path: false,
+        }
+    }
+}
+
+impl Visitor for Analyzer {
+    type Break = ();
+
+    fn pre_visit_statement(&mut self, statement: &Statement) -> ControlFlow<()> {
+        // The most far-reaching statement decides, e.g. a SELECT with a DELETE in a CTE is DML
+        self.class = self.class.max(statement_class(statement));
+        match statement {
+            Statement::Drop { names, .. } => self.relations.extend(names.iter().cloned()),
+            // SET search_path. Its alias SET SCHEMA does not parse, so it is rejected anyway
+            Statement::SetVariable { variables, .. } => {
+                let names: &[ObjectName] = match variables {
+                    OneOrManyWithParens::One(name) => std::slice::from_ref(name),
+                    OneOrManyWithParens::Many(names) => names,
+                };
+                self.sets_search_path |= names.iter().any(|name| match name.0.as_slice() {
+                    [name] => ident_name(name) == "search_path",
+                    _ => false,
+                });
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        if let Expr::Function(function) = expr {
+            if let [.., schema, name] = function.name.0.as_slice() {
+                self.functions.push((ident_name(schema), ident_name(name)));
+            }
+            match set_config_setting(function) {
+                Some(Some(setting)) => {
+                    self.sets_search_path |= setting.eq_ignore_ascii_case("search_path");
+                }
+                Some(None) => self.sets_search_path = true,
+                None => {}
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        if let Some(with) = &query.with {
+            self.ctes.extend(
+                with.cte_tables
+                    .iter()
+                    .map(|cte| ident_name(&cte.alias.name)),
+            );
+        }
+        match query.body.as_ref() {
+            SetExpr::Select(select) if select.into.is_some() => {
+                self.class = self.class.max(StatementClass::Ddl);
+            }
+            SetExpr::Insert(_) | SetExpr::Update(_) => {
+                self.class = self.class.max(StatementClass::Dml);
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_relation(&mut self, relation: &ObjectName) -> ControlFlow<()> {
+        self.relations.push(relation.clone());
+        ControlFlow::Continue(())
+    }
+}
+
+fn statement_class(statement: &Statement) -> StatementClass {
+    match statement {
+        Statement::Query(_) | Statement::Explain { .. } | Statement::ExplainTable { .. } => {
+            StatementClass::Select
+        }
+
+        Statement::Insert(_)
+        | Statement::Update { .. }
+        | Statement::Delete(_)
+        | Statement::Merge { .. }
+        | Statement::Truncate { .. }
+        | Statement::Copy { .. }
+        | Statement::Call(_) => StatementClass::Dml,
+
+        Statement::CreateView { .. }
+        | Statement::CreateTable(_)
+        | Statement::CreateVirtualTable { .. }
+        | Statement::CreateIndex(_)
+        | Statement::CreateRole { .. }
+        | Statement::CreatePolicy { .. }
+        | Statement::CreateExtension { .. }
+        | Statement::CreateSchema { .. }
+        | Statement::CreateDatabase { .. }
+        | Statement::CreateFunction(_)
+        | Statement::CreateTrigger { .. }
+        | Statement::CreateProcedure { .. }
+        | Statement::CreateSequence { .. }
+        | Statement::CreateType { .. }
+        | Statement::AlterTable { .. }
+        | Statement::AlterIndex { .. }
+        | Statement::AlterView { .. }
+        | Statement::AlterRole { .. }
+        | Statement::AlterPolicy { .. }
+        | Statement::Drop { .. }
+        | Statement::DropFunction { .. }
+        | Statement::DropProcedure { .. }
+        | Statement::DropPolicy { .. }
+        | Statement::DropTrigger { .. }
+        | Statement::Comment { .. }
+        | Statement::Grant { .. }
+        | Statement::Revoke { .. } => StatementClass::Ddl,
+
+        _ => StatementClass::Utility,
+    }
+}
+
+/// Returns (schema, table) for a table reference. Unqualified names are assumed to be
+/// in `public`, the first schema of the default search path.
+fn qualified_name(name: &ObjectName) -> (String, String) {
+    let parts: Vec<String> = name.0.iter().map(ident_name).collect();
+    match parts.as_slice() {
+        [.., schema, table] => (schema.clone(), table.clone()),
+        [table] => ("public".to_string(), table.clone()),
+        [] => ("public".to_string(), String::new()),
+    }
+}
+
+/// Folds unquoted identifiers to lower case, as PostgreSQL does.
+fn ident_name(ident: &sqlparser::ast::Ident) -> String {
+    if ident.quote_style.is_some() {
+        ident.value.clone()
+    } else {
+        ident.value.to_lowercase()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classes(sql: &str) -> Vec<StatementClass> {
+        classify(sql).unwrap().statements
+    }
+
+    fn rule(allow: Option<Vec<StatementClass>>, deny: Option<Vec<StatementClass>>) -> PolicyRule {
+        PolicyRule {
+            name: "test".to_string(),
+            role: None,
+            claim: None,
+            value: None,
+            allow,
+            deny,
+            schemas: None,
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        use StatementClass::*;
+
+        assert_eq!(classes("SELECT * FROM users"), vec![Select]);
+        assert_eq!(classes("EXPLAIN SELECT 1"), vec![Select]);
+        assert_eq!(classes("UPDATE users SET name = 'x'"), vec![Dml]);
+        assert_eq!(
+            classes(
+                "WITH changed AS (UPDATE users SET name = 'x' RETURNING id) SELECT * FROM changed"
+            ),
+            vec![Dml]
+        );
+        assert_eq!(classes("SELECT * INTO copy FROM users"), vec![Ddl]);
+        assert_eq!(classes("DROP TABLE users"), vec![Ddl]);
+        assert_eq!(classes("GRANT SELECT ON users TO analyst"), vec![Ddl]);
+        assert_eq!(classes("SET ROLE postgres"), vec![Utility]);
+        assert_eq!(classes("SELECT 1; DELETE FROM users"), vec![Select, Dml]);
+        assert!(matches!(classify("SELEC 1"), Err(PolicyError::Parse(_))));
+    }
+
+    #[test]
+    fn test_session_violation() {
+        for sql in [
+            "COMMIT",
+            "select 1; commit",
+            "BEGIN",
+            "START TRANSACTION",
+            "ROLLBACK",
+            "SAVEPOINT a",
+            "RELEASE SAVEPOINT a",
+            "END",
+            "ABORT",
+            "PREPARE TRANSACTION 'x'",
+            "SET ROLE postgres",
+            "SET LOCAL ROLE postgres",
+            "set role = 'postgres'",
+            "SET session_authorization TO postgres",
+            "RESET ROLE",
+            "SELECT 1; /* reset */ RESET ROLE",
+            "SET SESSION AUTHORIZATION postgres",
+            "SET LOCAL SESSION AUTHORIZATION DEFAULT",
+            "RESET SESSION AUTHORIZATION",
+            "SELECT set_config('role', 'postgres', true)",
+            "SELECT pg_catalog.set_config('session_authorization', 'postgres', false)",
+            "WITH x AS (SELECT set_config('ROLE', 'postgres', true)) SELECT * FROM x",
+            "SELECT 1 WHERE set_config($$role$$, 'postgres', true) IS NOT NULL",
+            "SELECT set_config('request.jwt.claims', '{}', true)",
+            "SELECT set_config('request.jwt.claim.sub', 'other', true)",
+            "SELECT set_config('ro' || 'le', 'postgres', true)",
+            "SELECT set_config($1, $2, true)",
+            "SELEC 1; SELECT set_config('role', 'postgres', true)",
+            "SELEC 1; SELECT set_config('rol' || 'e', 'postgres', true)",
+            "SET request.jwt.claims = '{}'",
+            "SET LOCAL request.jwt.claim.sub TO 'other'",
+            "SET LOCAL \"request\".jwt.claims = '{}'",
+            "RESET request.jwt.claims",
+            "SELECT 1; SET LOCAL request.jwt.claims TO '{}'; SELEC 2",
+        ] {
+            assert!(session_violation(sql).is_some(), "{} was allowed", sql);
+        }
+        assert_eq!(
+            session_violation("SET ROLE NONE"),
+            Some("role changes are not allowed".to_string())
+        );
+        assert_eq!(
+            session_violation("SELECT set_config('role', 'postgres', true)"),
+            Some("role changes are not allowed".to_string())
+        );
+
+        for sql in [
+            "SELECT 'commit'",
+            "UPDATE t SET role = 'admin'",
+            "SET search_path TO app",
+            "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE",
+            "RESET search_path",
+            "SELECT 1; SELEC 2",
+            "SELECT set_config('search_path', 'app', true)",
+            "SELECT current_setting('request.jwt.claims', true)",
+            "SELEC 1; SELECT set_config('search_path', 'app', true)",
+            "SET request.timeout = '5s'",
+            "RESET app.setting",
+        ] {
+            assert!(session_violation(sql).is_none(), "{} was rejected", sql);
+        }
+    }
+
+    #[test]
+    fn test_classify_tables() {
+        let classification = classify(
+            "WITH recent AS (SELECT * FROM reporting.sales) \
+             SELECT * FROM recent JOIN Customers c ON true JOIN \"Reporting\".x ON true",
+        )
+        .unwrap();
+        assert_eq!(
+            classification.tables,
+            vec![
+                ("reporting".to_string(), "sales".to_string()),
+                ("public".to_string(), "customers".to_string()),
+                ("Reporting".to_string(), "x".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_violation() {
+        use StatementClass::*;
+
+        let select_only = rule(Some(vec![Select]), None);
+        assert!(violation(&select_only, &classify("SELECT 1").unwrap()).is_none());
+        assert!(violation(&select_only, &classify("DELETE FROM t").unwrap()).is_some());
+        assert!(violation(&select_only, &classify("SELECT 1; SELECT 2").unwrap()).is_some());
+
+        let no_ddl = rule(None, Some(vec![Ddl]));
+        assert!(violation(&no_ddl, &classify("INSERT INTO t VALUES (1)").unwrap()).is_none());
+        assert_eq!(
+            violation(&no_ddl, &classify("CREATE TABLE t (x int)").unwrap()),
+            Some("DDL statements are not allowed".to_string())
+        );
+
+        let reporting = PolicyRule {
+            schemas: Some(vec!["reporting".to_string()]),
+            ..rule(None, None)
+        };
+        assert!(violation(
+            &reporting,
+            &classify("SELECT * FROM reporting.sales").unwrap()
+        )
+        .is_none());
+        assert!(violation(&reporting, &classify("SELECT * FROM users").unwrap()).is_some());
+        assert_eq!(
+            violation(&reporting, &classify("SELECT secret.f()").unwrap()),
+            Some("function secret.f is outside the allowed schemas".to_string())
+        );
+        assert!(violation(
+            &reporting,
+            &classify("SELECT reporting.total(), now()").unwrap()
+        )
+        .is_none());
+        for sql in [
+            "SET search_path = secret",
+            "SET LOCAL search_path TO reporting",
+            "SELECT set_config('search_path', 'secret', true)",
+            "SELECT set_config($1, 'secret', true)",
+        ] {
+            assert_eq!(
+                violation(&reporting, &classify(sql).unwrap()),
+                Some("changing search_path is not allowed".to_string()),
+                "{}",
+                sql
+            );
+        }
+        // Without a schemas restriction search_path may change
+        assert!(violation(&no_ddl, &classify("SET search_path = app").unwrap()).is_none());
+    }
+}
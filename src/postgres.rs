@@ -1,91 +1,372 @@
 use anyhow::Result;
-use std::sync::Arc;
-use tokio::sync::Semaphore;
-use tokio_postgres::{Client, NoTls};
-use tracing::{info, warn};
+use futures_util::{stream, StreamExt};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_postgres::config::SslMode as PgSslMode;
+use tokio_postgres::{AsyncMessage, CancelToken, Client, Connection, Notification};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tracing::{debug, info, warn};
 
-use crate::config::DatabaseConfig;
+use crate::config::{DatabaseConfig, SslMode};
+use crate::sql::quote_ident;
+use crate::tls;
+
+const DEFAULT_MAX_LIFETIME_SECONDS: u64 = 1800;
+const DEFAULT_IDLE_TIMEOUT_SECONDS: u64 = 600;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(30);
+/// Notifications a listener may fall behind by before its connection is closed.
+const NOTIFICATION_BUFFER: usize = 1024;
 
 #[derive(Clone)]
 pub struct PostgresPool {
-    config: DatabaseConfig,
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    pg_config: tokio_postgres::Config,
+    tls: MakeRustlsConnect,
     semaphore: Arc<Semaphore>,
+    idle: Mutex<VecDeque<IdleConnection>>,
+    max_connections: usize,
+    min_idle: usize,
+    max_lifetime: Duration,
+    idle_timeout: Duration,
+}
+
+struct PooledConnection {
+    client: Client,
+    created_at: Instant,
+}
+
+struct IdleConnection {
+    conn: PooledConnection,
+    idle_since: Instant,
 }
 
 impl PostgresPool {
     pub async fn new(config: &DatabaseConfig) -> Result<Self> {
         println!("Creating PostgreSQL connection pool...");
         eprintln!("Creating PostgreSQL connection pool...");
-        
-        // Test connection
-        let connection_string = format!(
-            "host={} port={} user={} password={} dbname={}",
-            config.host, config.port, config.username, config.password, config.database
-        );
 
-        println!("Connecting to database: host={} port={} user={} dbname={}", 
-                config.host, config.port, config.username, config.database);
+        let mut pg_config = tokio_postgres::Config::new();
+        pg_config
+            .host(&config.host)
+            .port(config.port)
+            .user(&config.username)
+            .password(&config.password)
+            .dbname(&config.database)
+            .ssl_mode(match config.sslmode.unwrap_or_default() {
+                SslMode::Disable => PgSslMode::Disable,
+                SslMode::Prefer => PgSslMode::Prefer,
+                SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => PgSslMode::Require,
+            });
 
-        let (client, connection) = tokio_postgres::connect(&connection_string, NoTls).await?;
+        println!("Connecting to database: host={} port={} user={} dbname={} sslmode={:?}",
+                config.host, config.port, config.username, config.database,
+                config.sslmode.unwrap_or_default());
 
-        // Spawn the connection in the background
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                warn!("Database connection error: {}", e);
-            }
+        let max_connections = config.max_connections as usize;
+        let inner = Arc::new(PoolInner {
+            pg_config,
+            tls: tls::make_connector(config)?,
+            semaphore: Arc::new(Semaphore::new(max_connections)),
+            idle: Mutex::new(VecDeque::new()),
+            max_connections,
+            min_idle: (config.min_idle.unwrap_or(0) as usize).min(max_connections),
+            max_lifetime: Duration::from_secs(
+                config.max_lifetime_seconds.unwrap_or(DEFAULT_MAX_LIFETIME_SECONDS),
+            ),
+            idle_timeout: Duration::from_secs(
+                config.idle_timeout_seconds.unwrap_or(DEFAULT_IDLE_TIMEOUT_SECONDS),
+            ),
         });
 
-        // Test the connection
+        // Test connection
+        let conn = inner.connect().await?;
+
         println!("Testing database connection...");
-        client.simple_query("SELECT 1").await?;
+        conn.client.simple_query("SELECT 1").await?;
         println!("Database connection test successful");
         info!("Database connection test successful");
 
-        Ok(Self {
-            config: config.clone(),
-            semaphore: Arc::new(Semaphore::new(config.max_connections as usize)),
-        })
+        inner.release(conn);
+        inner.fill_idle().await;
+
+        let weak = Arc::downgrade(&inner);
+        tokio::spawn(async move {
+            run_maintenance(weak).await;
+        });
+
+        Ok(Self { inner })
     }
 
     pub async fn get_client(&self) -> Result<PostgresClient> {
         // Acquire a permit from the semaphore
-        let permit = self.semaphore.clone().acquire_owned().await?;
+        let permit = self.inner.semaphore.clone().acquire_owned().await?;
+
+        // Reuse an idle connection if a live one is available
+        while let Some(conn) = self.inner.take_idle() {
+            if self.inner.is_expired(&conn) {
+                debug!("Closing pooled connection that exceeded max_lifetime");
+                continue;
+            }
+
+            // DISCARD ALL resets any session state left by the previous user and
+            // doubles as the liveness check before the connection is handed out.
+            match conn.client.simple_query("DISCARD ALL").await {
+                Ok(_) => {
+                    // Server-side statements used for type lookups are gone now
+                    conn.client.clear_type_cache();
+                    return Ok(PostgresClient {
+                        conn: Some(conn),
+                        pool: self.inner.clone(),
+                        discard: Arc::new(AtomicBool::new(false)),
+                        _permit: permit,
+                    });
+                }
+                Err(e) => {
+                    debug!("Discarding broken pooled connection: {}", e);
+                }
+            }
+        }
 
         // Create a new connection
-        let connection_string = format!(
-            "host={} port={} user={} password={} dbname={}",
-            self.config.host,
-            self.config.port,
-            self.config.username,
-            self.config.password,
-            self.config.database
-        );
+        let conn = self.inner.connect().await?;
+
+        Ok(PostgresClient {
+            conn: Some(conn),
+            pool: self.inner.clone(),
+            discard: Arc::new(AtomicBool::new(false)),
+            _permit: permit,
+        })
+    }
+
+    /// Opens a dedicated connection, outside the pool, that receives notifications.
+    pub async fn listener(&self) -> Result<Listener> {
+        let (client, connection) = self.inner.pg_config.connect(self.inner.tls.clone()).await?;
+        let (sender, notifications) = mpsc::channel(NOTIFICATION_BUFFER);
+        tokio::spawn(drive(connection, Some(sender)));
+
+        Ok(Listener {
+            client,
+            notifications,
+        })
+    }
+}
 
-        let (client, connection) = tokio_postgres::connect(&connection_string, NoTls).await?;
+impl PoolInner {
+    async fn connect(&self) -> Result<PooledConnection> {
+        let (client, connection) = self.pg_config.connect(self.tls.clone()).await?;
 
         // Spawn the connection in the background
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                warn!("Database connection error: {}", e);
-            }
-        });
+        tokio::spawn(drive(connection, None));
 
-        Ok(PostgresClient {
+        Ok(PooledConnection {
             client,
-            _permit: permit,
+            created_at: Instant::now(),
         })
     }
+
+    fn take_idle(&self) -> Option<PooledConnection> {
+        self.idle.lock().unwrap().pop_back().map(|idle| idle.conn)
+    }
+
+    fn is_expired(&self, conn: &PooledConnection) -> bool {
+        conn.created_at.elapsed() >= self.max_lifetime
+    }
+
+    fn release(&self, conn: PooledConnection) {
+        if conn.client.is_closed() || self.is_expired(&conn) {
+            return;
+        }
+
+        self.idle.lock().unwrap().push_back(IdleConnection {
+            conn,
+            idle_since: Instant::now(),
+        });
+    }
+
+    /// Closes connections past `max_lifetime`, and idle connections above
+    /// `min_idle` that have not been used for `idle_timeout`.
+    fn prune_idle(&self) {
+        let mut idle = self.idle.lock().unwrap();
+        let before = idle.len();
+        idle.retain(|entry| !entry.conn.client.is_closed() && !self.is_expired(&entry.conn));
+        // The front of the queue holds the connections that have been idle longest
+        while idle.len() > self.min_idle
+            && idle
+                .front()
+                .is_some_and(|entry| entry.idle_since.elapsed() >= self.idle_timeout)
+        {
+            idle.pop_front();
+        }
+        let pruned = before - idle.len();
+        if pruned > 0 {
+            debug!("Closed {} idle database connections", pruned);
+        }
+    }
+
+    /// Opens connections until `min_idle` are available, without exceeding `max_connections`.
+    async fn fill_idle(&self) {
+        loop {
+            let idle = self.idle.lock().unwrap().len();
+            let in_use = self.max_connections - self.semaphore.available_permits();
+            if idle >= self.min_idle || idle + in_use >= self.max_connections {
+                return;
+            }
+
+            // The permit counts the connection being opened as in use, so requests
+            // cannot open connections alongside it past the limit
+            let Ok(_permit) = self.semaphore.try_acquire() else {
+                return;
+            };
+            match self.connect().await {
+                Ok(conn) => self.release(conn),
+                Err(e) => {
+                    warn!("Failed to open idle database connection: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Runs a connection until it closes, passing notifications to `notifications` if set
+/// and dropping them otherwise. If `notifications` is full, the connection is closed.
+async fn drive<S, T>(
+    mut connection: Connection<S, T>,
+    notifications: Option<mpsc::Sender<Notification>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+    while let Some(message) = messages.next().await {
+        match message {
+            Ok(AsyncMessage::Notification(notification)) => {
+                if let Some(sender) = &notifications {
+                    // A closed channel means the listener is gone; the connection closes
+                    // once its client is dropped
+                    if let Err(TrySendError::Full(_)) = sender.try_send(notification) {
+                        warn!(
+                            "Listener is {} notifications behind, closing its connection",
+                            NOTIFICATION_BUFFER
+                        );
+                        return;
+                    }
+                }
+            }
+            Ok(AsyncMessage::Notice(notice)) => {
+                debug!("Database notice: {}", notice.message());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Database connection error: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+async fn run_maintenance(pool: Weak<PoolInner>) {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        pool.prune_idle();
+        pool.fill_idle().await;
+    }
 }
 
 pub struct PostgresClient {
-    client: Client,
+    conn: Option<PooledConnection>,
+    pool: Arc<PoolInner>,
+    /// Set once a cancel request has been sent. Such a request may arrive after the
+    /// statement it targeted has finished, so the connection is closed on drop rather
+    /// than returned to the pool where it could cancel another request's statement.
+    discard: Arc<AtomicBool>,
     _permit: tokio::sync::OwnedSemaphorePermit,
 }
 
+impl PostgresClient {
+    /// Returns a handle that can cancel the statement running on this connection.
+    pub fn canceller(&self) -> QueryCanceller {
+        QueryCanceller {
+            token: self.cancel_token(),
+            tls: self.pool.tls.clone(),
+            discard: self.discard.clone(),
+        }
+    }
+}
+
+/// A connection that has run `LISTEN` and receives the notifications sent to its
+/// channels. The connection is closed when the listener is dropped.
+pub struct Listener {
+    client: Client,
+    notifications: mpsc::Receiver<Notification>,
+}
+
+impl Listener {
+    pub async fn listen(&self, channel: &str) -> Result<(), tokio_postgres::Error> {
+        self.client
+            .batch_execute(&format!("LISTEN {}", quote_ident(channel)))
+            .await
+    }
+
+    /// Waits for the next notification; `None` once the connection is closed, including
+    /// when the caller fell too far behind.
+    pub async fn next(&mut self) -> Option<Notification> {
+        self.notifications.recv().await
+    }
+}
+
+pub struct QueryCanceller {
+    token: CancelToken,
+    tls: MakeRustlsConnect,
+    discard: Arc<AtomicBool>,
+}
+
+impl QueryCanceller {
+    /// Cancels the running statement. The connection is closed instead of being
+    /// returned to the pool once its client is dropped.
+    pub async fn cancel(&self) {
+        self.discard.store(true, Ordering::Relaxed);
+        if let Err(e) = self.token.cancel_query(self.tls.clone()).await {
+            warn!("Failed to cancel query: {}", e);
+        }
+    }
+}
+
 impl std::ops::Deref for PostgresClient {
     type Target = Client;
 
     fn deref(&self) -> &Self::Target {
-        &self.client
+        &self.conn.as_ref().expect("connection is present until drop").client
+    }
+}
+
+impl std::ops::DerefMut for PostgresClient {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.conn.as_mut().expect("connection is present until drop").client
+    }
+}
+
+impl Drop for PostgresClient {
+    fn drop(&mut self) {
+        // Return the connection to the pool before the permit is released
+        if let Some(conn) = self.conn.take() {
+            if self.discard.load(Ordering::Relaxed) {
+                debug!("Closing connection after a cancelled statement");
+                return;
+            }
+            self.pool.release(conn);
+        }
     }
 }
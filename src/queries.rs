@@ -0,0 +1,273 @@
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Value};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::collections::{HashMap, HashSet};
+
+use crate::config::{NamedQuery, ParamType, QueryParam};
+use crate::oidc::Claims;
+use crate::session;
+
+/// The named queries from the configuration, looked up by name.
+pub struct QueryRegistry {
+    queries: HashMap<String, NamedQuery>,
+}
+
+impl QueryRegistry {
+    /// Builds the registry, rejecting definitions that could never be called correctly.
+    pub fn new(queries: &[NamedQuery]) -> Result<Self> {
+        let mut registry = HashMap::new();
+        for query in queries {
+            validate(query).map_err(|e| anyhow!("Invalid named query '{}': {}", query.name, e))?;
+            if registry.insert(query.name.clone(), query.clone()).is_some() {
+                bail!("Named query '{}' is defined more than once", query.name);
+            }
+        }
+        Ok(Self { queries: registry })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&NamedQuery> {
+        self.queries.get(name)
+    }
+}
+
+fn validate(query: &NamedQuery) -> Result<()> {
+    // The name is a single path segment of /queries/{name}
+    if query.name.is_empty()
+        || !query
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        bail!("names may only contain letters, digits, '_', '-' and '.'");
+    }
+    if query.claim.is_some() != query.value.is_some() {
+        bail!("claim and value must be set together");
+    }
+
+    let mut names = HashSet::new();
+    for param in query.params.iter().flatten() {
+        if !names.insert(param.name.as_str()) {
+            bail!("parameter '{}' is defined more than once", param.name);
+        }
+        if let Some(default) = &param.default {
+            if !default.is_null() && !has_type(default, param.param_type) {
+                bail!(
+                    "default of parameter '{}' is not of type {}",
+                    param.name,
+                    type_name(param.param_type)
+                );
+            }
+        }
+    }
+
+    // Parameters are bound to $1..$n by position, so every one needs a placeholder
+    let placeholders = max_placeholder(&query.sql)?;
+    if placeholders != names.len() {
+        bail!(
+            "the SQL uses {} placeholders but {} parameters are defined",
+            placeholders,
+            names.len()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the highest `$n` placeholder in `sql`, ignoring string literals and comments.
+fn max_placeholder(sql: &str) -> Result<usize> {
+    let tokens = Tokenizer::new(&PostgreSqlDialect {}, sql)
+        .tokenize()
+        .map_err(|e| anyhow!("failed to parse SQL: {}", e))?;
+    let mut max = 0;
+    for token in tokens {
+        if let Token::Placeholder(placeholder) = token {
+            let index = placeholder
+                .strip_prefix('$')
+                .and_then(|n| n.parse::<usize>().ok())
+                .ok_or_else(|| anyhow!("unsupported placeholder '{}'", placeholder))?;
+            max = max.max(index);
+        }
+    }
+    Ok(max)
+}
+
+/// Checks the role and claim a query requires against the caller.
+pub fn is_allowed(query: &NamedQuery, role: Option<&str>, claims: &Claims) -> bool {
+    let role_matches = query
+        .role
+        .as_deref()
+        .is_none_or(|required| role == Some(required));
+    let claim_matches = match (&query.claim, &query.value) {
+        (Some(claim), Some(value)) => session::claim_matches(claims, claim, value),
+        _ => true,
+    };
+    role_matches && claim_matches
+}
+
+/// Validates the arguments of a call against the query's parameters and returns them
+/// as positional parameters in `$1..$n` order.
+pub fn bind_args(query: &NamedQuery, args: &Map<String, Value>) -> Result<Vec<Value>> {
+    let params: &[QueryParam] = query.params.as_deref().unwrap_or_default();
+
+    if let Some(unknown) = args
+        .keys()
+        .find(|name| !params.iter().any(|param| &param.name == *name))
+    {
+        bail!("Unknown argument '{}'", unknown);
+    }
+
+    params
+        .iter()
+        .map(|param| {
+            let value = args.get(&param.name).or(param.default.as_ref());
+            let required = param.required.unwrap_or(param.default.is_none());
+            match value {
+                None | Some(Value::Null) if required => {
+                    Err(anyhow!("Missing required argument '{}'", param.name))
+                }
+                None | Some(Value::Null) => Ok(Value::Null),
+                Some(value) if has_type(value, param.param_type) => Ok(value.clone()),
+                Some(_) => Err(anyhow!(
+                    "Argument '{}' must be of type {}",
+                    param.name,
+                    type_name(param.param_type)
+                )),
+            }
+        })
+        .collect()
+}
+
+fn has_type(value: &Value, param_type: ParamType) -> bool {
+    match param_type {
+        ParamType::String => value.is_string(),
+        ParamType::Integer => value.is_i64() || value.is_u64(),
+        ParamType::Number => value.is_number(),
+        ParamType::Boolean => value.is_boolean(),
+        ParamType::Array => value.is_array(),
+        ParamType::Object => value.is_object(),
+    }
+}
+
+fn type_name(param_type: ParamType) -> &'static str {
+    match param_type {
+        ParamType::String => "string",
+        ParamType::Integer => "integer",
+        ParamType::Number => "number",
+        ParamType::Boolean => "boolean",
+        ParamType::Array => "array",
+        ParamType::Object => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn param(name: &str, param_type: ParamType, default: Option<Value>) -> QueryParam {
+        QueryParam {
+            name: name.to_string(),
+            param_type,
+            required: None,
+            default,
+        }
+    }
+
+    fn query(params: Vec<QueryParam>) -> NamedQuery {
+        NamedQuery {
+            name: "orders_by_customer".to_string(),
+            sql: "SELECT * FROM orders WHERE customer_id = $1 LIMIT $2".to_string(),
+            params: Some(params),
+            role: None,
+            claim: None,
+            value: None,
+            read_only: None,
+        }
+    }
+
+    fn args(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_bind_args() {
+        let query = query(vec![
+            param("customer_id", ParamType::Integer, None),
+            param("limit", ParamType::Integer, Some(json!(100))),
+        ]);
+
+        assert_eq!(
+            bind_args(&query, &args(json!({"customer_id": 7}))).unwrap(),
+            vec![json!(7), json!(100)]
+        );
+        assert_eq!(
+            bind_args(&query, &args(json!({"limit": 5, "customer_id": 7}))).unwrap(),
+            vec![json!(7), json!(5)]
+        );
+        assert!(bind_args(&query, &args(json!({}))).is_err());
+        assert!(bind_args(&query, &args(json!({"customer_id": null}))).is_err());
+        assert!(bind_args(&query, &args(json!({"customer_id": "7"}))).is_err());
+        assert!(bind_args(&query, &args(json!({"customer_id": 1.5}))).is_err());
+        assert!(bind_args(&query, &args(json!({"customer_id": 7, "other": 1}))).is_err());
+    }
+
+    #[test]
+    fn test_optional_param() {
+        let optional = QueryParam {
+            required: Some(false),
+            ..param("note", ParamType::String, None)
+        };
+        let query = query(vec![optional]);
+
+        assert_eq!(bind_args(&query, &args(json!({}))).unwrap(), vec![Value::Null]);
+        assert_eq!(
+            bind_args(&query, &args(json!({"note": "hi"}))).unwrap(),
+            vec![json!("hi")]
+        );
+    }
+
+    #[test]
+    fn test_registry_validation() {
+        let valid = || {
+            query(vec![
+                param("customer_id", ParamType::Integer, None),
+                param("limit", ParamType::Integer, None),
+            ])
+        };
+        assert!(QueryRegistry::new(&[valid()]).is_ok());
+        assert!(QueryRegistry::new(&[valid(), valid()]).is_err());
+
+        let bad_name = NamedQuery {
+            name: "orders/all".to_string(),
+            ..valid()
+        };
+        assert!(QueryRegistry::new(&[bad_name]).is_err());
+
+        let bad_default = query(vec![
+            param("customer_id", ParamType::Integer, None),
+            param("limit", ParamType::Integer, Some(json!("10"))),
+        ]);
+        assert!(QueryRegistry::new(&[bad_default]).is_err());
+
+        let claim_without_value = NamedQuery {
+            claim: Some("groups".to_string()),
+            ..valid()
+        };
+        assert!(QueryRegistry::new(&[claim_without_value]).is_err());
+    }
+
+    #[test]
+    fn test_placeholder_count() {
+        assert!(QueryRegistry::new(&[query(vec![])]).is_err());
+        assert!(
+            QueryRegistry::new(&[query(vec![param("customer_id", ParamType::Integer, None)])])
+                .is_err()
+        );
+
+        let literal = NamedQuery {
+            sql: "SELECT '$2' AS price, $1::int AS id -- costs $3".to_string(),
+            ..query(vec![param("id", ParamType::Integer, None)])
+        };
+        assert!(QueryRegistry::new(&[literal]).is_ok());
+    }
+}
@@ -0,0 +1,670 @@
+use anyhow::{anyhow, bail, Result};
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::{Map, Value};
+use tracing::{info, warn};
+
+use crate::catalog::{Table, TableKind};
+use crate::format::{CsvOptions, ResponseFormat};
+use crate::oidc::AuthenticatedUser;
+use crate::sql::quote_ident;
+use crate::stream::{self, StreamSession};
+use crate::{
+    api_error, begin_session, db_error, enforce_policy, get_client, mapped_role, run_statement,
+    ApiError, AppState,
+};
+
+/// Query string parameters, in request order.
+type QueryParams = Vec<(String, String)>;
+
+/// Query string keys that control the result rather than filter rows.
+const RESERVED_PARAMS: [&str; 4] = ["select", "order", "limit", "offset"];
+
+/// A row filter given as `column=operator.value` in the query string.
+#[derive(Debug, PartialEq)]
+pub struct Filter {
+    pub column: String,
+    pub negated: bool,
+    pub operator: Operator,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Operator {
+    /// `eq`, `neq`, `gt`, `gte`, `lt`, `lte`, compared as the column's type
+    Compare(&'static str, String),
+    /// `like` and `ilike`, matched against the column's text form
+    Like(&'static str, String),
+    /// `in.(a,b,c)`
+    In(Vec<String>),
+    /// `is.null`, `is.true`, `is.false`, `is.unknown`
+    Is(&'static str),
+}
+
+/// A parsed `GET` request.
+#[derive(Debug, Default)]
+pub struct ReadQuery {
+    pub columns: Vec<String>,
+    pub filters: Vec<Filter>,
+    /// `ORDER BY` terms, see `order_term`
+    pub order: Vec<String>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+pub async fn read_rows(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    headers: HeaderMap,
+    Path((schema, name)): Path<(String, String)>,
+    Query(params): Query<QueryParams>,
+) -> Result<Response, ApiError> {
+    info!("Reading {}.{} for user {}", schema, name, user.sub);
+
+    let table = find_table(&state, &schema, &name)?;
+    let query = parse_read_query(table, params).map_err(bad_request)?;
+    let (sql, params) = select_sql(table, &query);
+
+    let role = mapped_role(&state, &user)?;
+    enforce_policy(&state, &user, role.as_deref(), &sql)?;
+
+    let encoder = ResponseFormat::negotiate(None, &headers)
+        .encoder(&CsvOptions::default())
+        .map_err(bad_request)?;
+    let mut client = get_client(&state).await?;
+
+    if let Some(encoder) = encoder {
+        let AuthenticatedUser(claims) = user;
+        let session = StreamSession::New {
+            client,
+            role,
+            claims,
+        };
+        return stream::stream_query(session, sql, params, encoder).await;
+    }
+
+    let transaction = begin_session(&mut client, role.as_deref(), &user, true).await?;
+    let response = run_statement(&transaction, &sql, &params).await?;
+    transaction
+        .commit()
+        .await
+        .map_err(|e| db_error("Query execution failed", &e))?;
+
+    Ok(Json(Value::Array(response.rows)).into_response())
+}
+
+/// Inserts one row from a JSON object, or several from an array of objects, and returns
+/// the inserted rows.
+pub async fn insert_rows(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Path((schema, name)): Path<(String, String)>,
+    Json(body): Json<Value>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    info!("Inserting into {}.{} for user {}", schema, name, user.sub);
+
+    let table = writable_table(&state, &schema, &name)?;
+    let rows: Vec<Map<String, Value>> = match body {
+        Value::Object(row) => vec![row],
+        Value::Array(rows) => rows
+            .into_iter()
+            .map(|row| match row {
+                Value::Object(row) => Ok(row),
+                _ => Err(bad_request("Expected an array of objects")),
+            })
+            .collect::<Result<_, _>>()?,
+        _ => return Err(bad_request("Expected an object or an array of objects")),
+    };
+    let (sql, params) = insert_sql(table, &rows).map_err(bad_request)?;
+
+    let rows = run_write(&state, &user, &sql, &params).await?;
+    Ok((StatusCode::CREATED, Json(rows)))
+}
+
+/// Updates the row selected by its primary key and returns it.
+pub async fn update_rows(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Path((schema, name)): Path<(String, String)>,
+    Query(params): Query<QueryParams>,
+    Json(values): Json<Map<String, Value>>,
+) -> Result<Json<Value>, ApiError> {
+    info!("Updating {}.{} for user {}", schema, name, user.sub);
+
+    let table = writable_table(&state, &schema, &name)?;
+    let filters = parse_key_filters(table, params)?;
+    let (sql, params) = update_sql(table, &values, &filters).map_err(bad_request)?;
+
+    Ok(Json(run_write(&state, &user, &sql, &params).await?))
+}
+
+/// Deletes the row selected by its primary key and returns it.
+pub async fn delete_rows(
+    State(state): State<AppState>,
+    user: AuthenticatedUser,
+    Path((schema, name)): Path<(String, String)>,
+    Query(params): Query<QueryParams>,
+) -> Result<Json<Value>, ApiError> {
+    info!("Deleting from {}.{} for user {}", schema, name, user.sub);
+
+    let table = writable_table(&state, &schema, &name)?;
+    let filters = parse_key_filters(table, params)?;
+    let (sql, params) = delete_sql(table, &filters);
+
+    Ok(Json(run_write(&state, &user, &sql, &params).await?))
+}
+
+/// Runs a generated `INSERT`, `UPDATE` or `DELETE ... RETURNING *` as the caller's role.
+async fn run_write(
+    state: &AppState,
+    user: &AuthenticatedUser,
+    sql: &str,
+    params: &[Value],
+) -> Result<Value, ApiError> {
+    let role = mapped_role(state, user)?;
+    enforce_policy(state, user, role.as_deref(), sql)?;
+
+    let mut client = get_client(state).await?;
+    let transaction = begin_session(&mut client, role.as_deref(), user, false).await?;
+    let response = run_statement(&transaction, sql, params).await?;
+    transaction
+        .commit()
+        .await
+        .map_err(|e| db_error("Statement execution failed", &e))?;
+
+    Ok(Value::Array(response.rows))
+}
+
+fn find_table<'a>(state: &'a AppState, schema: &str, name: &str) -> Result<&'a Table, ApiError> {
+    state.catalog.table(schema, name).ok_or_else(|| {
+        api_error(
+            StatusCode::NOT_FOUND,
+            format!("Table {}.{} not found", schema, name),
+        )
+    })
+}
+
+fn writable_table<'a>(
+    state: &'a AppState,
+    schema: &str,
+    name: &str,
+) -> Result<&'a Table, ApiError> {
+    let table = find_table(state, schema, name)?;
+    if table.kind == TableKind::MaterializedView {
+        return Err(api_error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!(
+                "{}.{} is a materialized view and cannot be modified",
+                schema, name
+            ),
+        ));
+    }
+    Ok(table)
+}
+
+fn bad_request(e: impl ToString) -> ApiError {
+    let message = e.to_string();
+    warn!("Invalid table request: {}", message);
+    api_error(StatusCode::BAD_REQUEST, message)
+}
+
+fn parse_read_query(table: &Table, params: QueryParams) -> Result<ReadQuery> {
+    let mut query = ReadQuery::default();
+    for (key, value) in params {
+        match key.as_str() {
+            "select" => {
+                query.columns = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|column| !column.is_empty() && *column != "*")
+                    .map(|column| column_name(table, column))
+                    .collect::<Result<_>>()?;
+            }
+            "order" => {
+                query.order = value
+                    .split(',')
+                    .map(|term| order_term(table, term.trim()))
+                    .collect::<Result<_>>()?;
+            }
+            "limit" => query.limit = Some(parse_count("limit", &value)?),
+            "offset" => query.offset = Some(parse_count("offset", &value)?),
+            _ => query.filters.push(parse_filter(table, &key, &value)?),
+        }
+    }
+    Ok(query)
+}
+
+/// Parses the filters of a `PATCH` or `DELETE`, which must select rows by primary key.
+fn parse_key_filters(table: &Table, params: QueryParams) -> Result<Vec<Filter>, ApiError> {
+    if table.primary_key.is_empty() {
+        return Err(api_error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!(
+                "{}.{} has no primary key, so rows cannot be updated or deleted",
+                table.schema, table.name
+            ),
+        ));
+    }
+
+    let filters = params
+        .iter()
+        .map(|(key, value)| {
+            if RESERVED_PARAMS.contains(&key.as_str()) {
+                bail!("'{}' is only supported when reading rows", key);
+            }
+            parse_filter(table, key, value)
+        })
+        .collect::<Result<Vec<_>>>()
+        .map_err(bad_request)?;
+
+    // Guards against updating or deleting more than intended
+    let missing: Vec<&str> = table
+        .primary_key
+        .iter()
+        .filter(|key| {
+            !filters.iter().any(|filter| {
+                &filter.column == *key
+                    && !filter.negated
+                    && matches!(filter.operator, Operator::Compare("=", _))
+            })
+        })
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        return Err(bad_request(format!(
+            "An eq filter is required on every primary key column; missing: {}",
+            missing.join(", ")
+        )));
+    }
+    Ok(filters)
+}
+
+fn column_name(table: &Table, column: &str) -> Result<String> {
+    table
+        .column(column)
+        .map(|column| column.name.clone())
+        .ok_or_else(|| {
+            anyhow!(
+                "Column '{}' does not exist in {}.{}",
+                column,
+                table.schema,
+                table.name
+            )
+        })
+}
+
+fn parse_count(name: &str, value: &str) -> Result<u64> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("'{}' must be a non-negative integer", name))
+}
+
+/// Turns `column[.asc|.desc][.nullsfirst|.nullslast]` into an `ORDER BY` term.
+pub fn order_term(table: &Table, term: &str) -> Result<String> {
+    let mut parts = term.split('.');
+    let column = column_name(table, parts.next().unwrap_or_default())?;
+
+    let mut sql = quote_ident(&column);
+    for modifier in parts {
+        sql.push_str(match modifier {
+            "asc" => " ASC",
+            "desc" => " DESC",
+            "nullsfirst" => " NULLS FIRST",
+            "nullslast" => " NULLS LAST",
+            _ => bail!("Unknown order modifier '{}'", modifier),
+        });
+    }
+    Ok(sql)
+}
+
+/// Parses `operator.value`, optionally prefixed with `not.`.
+fn parse_filter(table: &Table, column: &str, expression: &str) -> Result<Filter> {
+    let column = column_name(table, column)?;
+    let (negated, expression) = match expression.strip_prefix("not.") {
+        Some(rest) => (true, rest),
+        None => (false, expression),
+    };
+    let (operator, value) = expression
+        .split_once('.')
+        .ok_or_else(|| anyhow!("Filter on '{}' must have the form operator.value", column))?;
+
+    let operator = match operator {
+        "eq" => Operator::Compare("=", value.to_string()),
+        "neq" => Operator::Compare("<>", value.to_string()),
+        "gt" => Operator::Compare(">", value.to_string()),
+        "gte" => Operator::Compare(">=", value.to_string()),
+        "lt" => Operator::Compare("<", value.to_string()),
+        "lte" => Operator::Compare("<=", value.to_string()),
+        "like" => Operator::Like("LIKE", value.to_string()),
+        "ilike" => Operator::Like("ILIKE", value.to_string()),
+        "in" => Operator::In(parse_list(value)?),
+        "is" => Operator::Is(match value {
+            "null" => "NULL",
+            "true" => "TRUE",
+            "false" => "FALSE",
+            "unknown" => "UNKNOWN",
+            _ => bail!("'is' filters take null, true, false or unknown"),
+        }),
+        _ => bail!("Unknown filter operator '{}'", operator),
+    };
+
+    Ok(Filter {
+        column,
+        negated,
+        operator,
+    })
+}
+
+/// Splits `(a,b,"c,d")` into its values; double quotes allow commas inside a value.
+fn parse_list(list: &str) -> Result<Vec<String>> {
+    let inner = list
+        .strip_prefix('(')
+        .and_then(|list| list.strip_suffix(')'))
+        .ok_or_else(|| anyhow!("'in' filters take a list such as in.(1,2,3)"))?;
+    if inner.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut values = Vec::new();
+    let mut value = String::new();
+    let mut quoted = false;
+    for c in inner.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => values.push(std::mem::take(&mut value)),
+            c => value.push(c),
+        }
+    }
+    if quoted {
+        bail!("Unterminated quote in 'in' filter");
+    }
+    values.push(value);
+    Ok(values)
+}
+
+/// Builds the `WHERE` clause; filter values are bound as text and cast to the column type.
+fn where_clause(table: &Table, filters: &[Filter], params: &mut Vec<Value>) -> String {
+    let mut bind = |value: &str, type_name: &str| {
+        params.push(Value::String(value.to_string()));
+        match type_name {
+            "text" => format!("${}::text", params.len()),
+            _ => format!("${}::text::{}", params.len(), type_name),
+        }
+    };
+
+    let conditions: Vec<String> = filters
+        .iter()
+        .map(|filter| {
+            let column = quote_ident(&filter.column);
+            let type_name = table
+                .column(&filter.column)
+                .map(|column| column.type_name.as_str())
+                .unwrap_or("text");
+            let condition = match &filter.operator {
+                Operator::Compare(op, value) => {
+                    format!("{} {} {}", column, op, bind(value, type_name))
+                }
+                Operator::Like(op, pattern) => {
+                    format!("{}::text {} {}", column, op, bind(pattern, "text"))
+                }
+                Operator::In(values) if values.is_empty() => "false".to_string(),
+                Operator::In(values) => {
+                    let values: Vec<String> =
+                        values.iter().map(|value| bind(value, type_name)).collect();
+                    format!("{} IN ({})", column, values.join(", "))
+                }
+                Operator::Is(value) => format!("{} IS {}", column, value),
+            };
+            if filter.negated {
+                format!("NOT ({})", condition)
+            } else {
+                condition
+            }
+        })
+        .collect();
+
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", conditions.join(" AND "))
+    }
+}
+
+fn table_name(table: &Table) -> String {
+    format!(
+        "{}.{}",
+        quote_ident(&table.schema),
+        quote_ident(&table.name)
+    )
+}
+
+pub fn select_sql(table: &Table, query: &ReadQuery) -> (String, Vec<Value>) {
+    let columns = if query.columns.is_empty() {
+        "*".to_string()
+    } else {
+        let columns: Vec<String> = query.columns.iter().map(|c| quote_ident(c)).collect();
+        columns.join(", ")
+    };
+
+    let mut params = Vec::new();
+    let mut sql = format!("SELECT {} FROM {}", columns, table_name(table));
+    sql.push_str(&where_clause(table, &query.filters, &mut params));
+    if !query.order.is_empty() {
+        sql.push_str(&format!(" ORDER BY {}", query.order.join(", ")));
+    }
+    if let Some(limit) = query.limit {
+        sql.push_str(&format!(" LIMIT {}", limit));
+    }
+    if let Some(offset) = query.offset {
+        sql.push_str(&format!(" OFFSET {}", offset));
+    }
+    (sql, params)
+}
+
+/// Builds a multi-row `INSERT`; columns missing from a row get their default.
+pub fn insert_sql(table: &Table, rows: &[Map<String, Value>]) -> Result<(String, Vec<Value>)> {
+    if rows.is_empty() {
+        bail!("No rows to insert");
+    }
+    if rows.len() > 1 && rows.iter().any(Map::is_empty) {
+        bail!("Empty rows can only be inserted one at a time");
+    }
+    for key in rows.iter().flat_map(Map::keys) {
+        column_name(table, key)?;
+    }
+    let columns: Vec<&str> = table
+        .columns
+        .iter()
+        .map(|column| column.name.as_str())
+        .filter(|column| rows.iter().any(|row| row.contains_key(*column)))
+        .collect();
+
+    let mut params = Vec::new();
+    let values: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let values: Vec<String> = columns
+                .iter()
+                .map(|column| match row.get(*column) {
+                    Some(value) => {
+                        params.push(value.clone());
+                        format!("${}", params.len())
+                    }
+                    None => "DEFAULT".to_string(),
+                })
+                .collect();
+            format!("({})", values.join(", "))
+        })
+        .collect();
+
+    let sql = if columns.is_empty() {
+        format!(
+            "INSERT INTO {} DEFAULT VALUES RETURNING *",
+            table_name(table)
+        )
+    } else {
+        let columns: Vec<String> = columns.iter().map(|c| quote_ident(c)).collect();
+        format!(
+            "INSERT INTO {} ({}) VALUES {} RETURNING *",
+            table_name(table),
+            columns.join(", "),
+            values.join(", ")
+        )
+    };
+    Ok((sql, params))
+}
+
+pub fn update_sql(
+    table: &Table,
+    values: &Map<String, Value>,
+    filters: &[Filter],
+) -> Result<(String, Vec<Value>)> {
+    if values.is_empty() {
+        bail!("No columns to update");
+    }
+
+    let mut params = Vec::new();
+    let assignments: Vec<String> = values
+        .iter()
+        .map(|(column, value)| {
+            let column = column_name(table, column)?;
+            params.push(value.clone());
+            Ok(format!("{} = ${}", quote_ident(&column), params.len()))
+        })
+        .collect::<Result<_>>()?;
+
+    let sql = format!(
+        "UPDATE {} SET {}{} RETURNING *",
+        table_name(table),
+        assignments.join(", "),
+        where_clause(table, filters, &mut params)
+    );
+    Ok((sql, params))
+}
+
+pub fn delete_sql(table: &Table, filters: &[Filter]) -> (String, Vec<Value>) {
+    let mut params = Vec::new();
+    let sql = format!(
+        "DELETE FROM {}{} RETURNING *",
+        table_name(table),
+        where_clause(table, filters, &mut params)
+    );
+    (sql, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::Column;
+    use serde_json::json;
+
+    fn users() -> Table {
+        let column = |name: &str, type_name: &str| Column {
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+            nullable: name != "id",
+        };
+        Table {
+            schema: "public".to_string(),
+            name: "users".to_string(),
+            kind: TableKind::Table,
+            columns: vec![
+                column("id", "integer"),
+                column("name", "character varying"),
+                column("created_at", "timestamp without time zone"),
+            ],
+            primary_key: vec!["id".to_string()],
+            foreign_keys: Vec::new(),
+        }
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> QueryParams {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        let table = users();
+        let filter = parse_filter(&table, "id", "not.in.(1,\"2,3\")").unwrap();
+        assert!(filter.negated);
+        assert_eq!(
+            filter.operator,
+            Operator::In(vec!["1".to_string(), "2,3".to_string()])
+        );
+        assert_eq!(
+            parse_filter(&table, "name", "eq.a.b").unwrap().operator,
+            Operator::Compare("=", "a.b".to_string())
+        );
+        assert_eq!(
+            parse_filter(&table, "name", "is.null").unwrap().operator,
+            Operator::Is("NULL")
+        );
+        assert!(parse_filter(&table, "missing", "eq.1").is_err());
+        assert!(parse_filter(&table, "id", "between.1").is_err());
+        assert!(parse_filter(&table, "id", "5").is_err());
+    }
+
+    #[test]
+    fn test_select_sql() {
+        let table = users();
+        let query = parse_read_query(
+            &table,
+            params(&[
+                ("select", "id,name"),
+                ("id", "gte.10"),
+                ("name", "ilike.a%"),
+                ("order", "created_at.desc.nullslast,id"),
+                ("limit", "5"),
+                ("offset", "10"),
+            ]),
+        )
+        .unwrap();
+        let (sql, values) = select_sql(&table, &query);
+        assert_eq!(
+            sql,
+            "SELECT \"id\", \"name\" FROM \"public\".\"users\" \
+             WHERE \"id\" >= $1::text::integer AND \"name\"::text ILIKE $2::text \
+             ORDER BY \"created_at\" DESC NULLS LAST, \"id\" LIMIT 5 OFFSET 10"
+        );
+        assert_eq!(values, vec![json!("10"), json!("a%")]);
+
+        assert!(parse_read_query(&table, params(&[("limit", "-1")])).is_err());
+        assert!(parse_read_query(&table, params(&[("order", "id.sideways")])).is_err());
+    }
+
+    #[test]
+    fn test_write_sql() {
+        let table = users();
+        let rows = vec![
+            json!({"name": "a", "id": 1}).as_object().unwrap().clone(),
+            json!({"name": "b"}).as_object().unwrap().clone(),
+        ];
+        let (sql, values) = insert_sql(&table, &rows).unwrap();
+        assert_eq!(
+            sql,
+            "INSERT INTO \"public\".\"users\" (\"id\", \"name\") \
+             VALUES ($1, $2), (DEFAULT, $3) RETURNING *"
+        );
+        assert_eq!(values, vec![json!(1), json!("a"), json!("b")]);
+
+        let Ok(filters) = parse_key_filters(&table, params(&[("id", "eq.7")])) else {
+            panic!("expected a primary key filter");
+        };
+        let update = json!({"name": "c"}).as_object().unwrap().clone();
+        let (sql, values) = update_sql(&table, &update, &filters).unwrap();
+        assert_eq!(
+            sql,
+            "UPDATE \"public\".\"users\" SET \"name\" = $1 \
+             WHERE \"id\" = $2::text::integer RETURNING *"
+        );
+        assert_eq!(values, vec![json!("c"), json!("7")]);
+
+        assert!(parse_key_filters(&table, params(&[("name", "eq.c")])).is_err());
+        assert!(parse_key_filters(&table, params(&[("id", "neq.7")])).is_err());
+        assert!(parse_key_filters(&table, params(&[("id", "eq.7"), ("limit", "1")])).is_err());
+    }
+}
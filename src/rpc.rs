@@ -0,0 +1,441 @@
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Value};
+use tokio_postgres::types::Type;
+use tokio_postgres::{GenericClient, Row};
+
+use crate::config::RpcConfig;
+use crate::sql::quote_ident;
+use crate::types::{self, SqlParam};
+
+/// Functions and procedures in these schemas cannot be called through `/rpc`.
+const SYSTEM_SCHEMAS: &[&str] = &["pg_catalog", "information_schema"];
+
+/// Schema whose routines can be called when `rpc.schemas` is not set.
+const DEFAULT_SCHEMA: &str = "public";
+
+/// Leaves out routines that belong to extensions; appended after `ROUTINES_QUERY`.
+const NOT_EXTENSION: &str = "
+      AND NOT EXISTS (
+          SELECT 1 FROM pg_depend d
+          WHERE d.classid = 'pg_proc'::regclass AND d.objid = p.oid AND d.deptype = 'e'
+      )";
+
+/// Callable functions and procedures; callers append conditions selecting which.
+const ROUTINES_QUERY: &str = "
+    SELECT n.nspname,
+           p.proname,
+           p.prokind = 'p' AS is_procedure,
+           p.proretset,
+           p.provolatile = 'v' AS is_volatile,
+           p.prorettype = 'void'::regtype AS returns_void,
+           t.typtype = 'c' OR p.prorettype = 'record'::regtype AS returns_row,
+           p.prorettype::regtype::text AS return_type,
+           p.pronargdefaults::int4,
+           coalesce(p.proargnames, '{}') AS arg_names,
+           coalesce(p.proargmodes::text[], '{}') AS arg_modes,
+           ARRAY(
+               SELECT unnest(coalesce(p.proallargtypes, p.proargtypes::oid[]))::regtype::text
+           ) AS arg_types
+    FROM pg_proc p
+    JOIN pg_namespace n ON n.oid = p.pronamespace
+    JOIN pg_type t ON t.oid = p.prorettype
+    WHERE p.prokind IN ('f', 'p')
+      AND p.prorettype NOT IN ('trigger'::regtype, 'event_trigger'::regtype)";
+
+/// A function or procedure that can be called with named arguments.
+#[derive(Debug, Clone)]
+pub struct Routine {
+    pub schema: String,
+    pub name: String,
+    pub is_procedure: bool,
+    pub returns: Returns,
+    /// Whether the routine may modify the database (not `STABLE` or `IMMUTABLE`)
+    pub is_volatile: bool,
+    /// Return type as accepted in a cast, e.g. `integer` or `record`
+    pub return_type: String,
+    pub args: Vec<RoutineArg>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Returns {
+    /// A single value, returned as-is
+    Scalar,
+    /// Nothing; the call returns `null`
+    Void,
+    /// One row, from a composite type or `OUT` parameters
+    Row,
+    /// Any number of rows (`SETOF` or `RETURNS TABLE`)
+    Set,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutineArg {
+    pub name: String,
+    pub type_name: String,
+    pub mode: ArgMode,
+    pub has_default: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgMode {
+    In,
+    Out,
+    InOut,
+    Variadic,
+    /// A column of `RETURNS TABLE`
+    Table,
+}
+
+impl ArgMode {
+    pub fn is_input(self) -> bool {
+        matches!(self, ArgMode::In | ArgMode::InOut | ArgMode::Variadic)
+    }
+}
+
+/// Splits `schema.function` from the request path. Names without a schema are looked
+/// up in `public`; system schemas are not exposed.
+pub fn parse_name(path: &str) -> Option<(&str, &str)> {
+    let (schema, name) = path.split_once('.').unwrap_or(("public", path));
+    if schema.is_empty()
+        || name.is_empty()
+        || SYSTEM_SCHEMAS.contains(&schema)
+        || schema.starts_with("pg_")
+    {
+        return None;
+    }
+    Some((schema, name))
+}
+
+/// Checks `schema` against `rpc.schemas`.
+pub fn is_exposed(config: Option<&RpcConfig>, schema: &str) -> bool {
+    match config.and_then(|c| c.schemas.as_ref()) {
+        Some(schemas) => schemas.iter().any(|s| s == schema),
+        None => schema == DEFAULT_SCHEMA,
+    }
+}
+
+/// Loads every overload of `schema.name`, unless it belongs to an extension.
+pub async fn lookup<C: GenericClient>(
+    client: &C,
+    schema: &str,
+    name: &str,
+) -> Result<Vec<Routine>, tokio_postgres::Error> {
+    let sql = format!(
+        "{}{} AND n.nspname = $1 AND p.proname = $2",
+        ROUTINES_QUERY, NOT_EXTENSION
+    );
+    let rows = client.query(&sql, &[&schema, &name]).await?;
+    Ok(rows.iter().map(routine_from_row).collect())
+}
+
+/// Loads the routines of `schemas`, or of all non-system schemas, leaving out those
+/// that belong to extensions.
+pub async fn list<C: GenericClient>(
+    client: &C,
+    schemas: Option<&[String]>,
+) -> Result<Vec<Routine>, tokio_postgres::Error> {
+    let sql = format!(
+        "{}
+      AND n.nspname <> 'information_schema'
+      AND n.nspname NOT LIKE 'pg\\_%'
+      AND ($1::text[] IS NULL OR n.nspname = ANY($1)){}
+    ORDER BY n.nspname, p.proname",
+        ROUTINES_QUERY, NOT_EXTENSION
+    );
+    let schemas = schemas.map(<[String]>::to_vec);
+    let rows = client.query(&sql, &[&schemas]).await?;
+    Ok(rows.iter().map(routine_from_row).collect())
+}
+
+fn routine_from_row(row: &Row) -> Routine {
+    let names: Vec<String> = row.get("arg_names");
+    let modes: Vec<String> = row.get("arg_modes");
+    let types: Vec<String> = row.get("arg_types");
+
+    let mut args: Vec<RoutineArg> = types
+        .into_iter()
+        .enumerate()
+        .map(|(i, type_name)| RoutineArg {
+            name: names.get(i).cloned().unwrap_or_default(),
+            type_name,
+            mode: match modes.get(i).map(String::as_str) {
+                Some("o") => ArgMode::Out,
+                Some("b") => ArgMode::InOut,
+                Some("v") => ArgMode::Variadic,
+                Some("t") => ArgMode::Table,
+                _ => ArgMode::In,
+            },
+            has_default: false,
+        })
+        .collect();
+
+    // Defaults belong to the last `pronargdefaults` input arguments
+    let defaults = row.get::<_, i32>("pronargdefaults") as usize;
+    args.iter_mut()
+        .filter(|arg| arg.mode.is_input())
+        .rev()
+        .take(defaults)
+        .for_each(|arg| arg.has_default = true);
+
+    let returns = if row.get("proretset") {
+        Returns::Set
+    } else if row.get("returns_void") {
+        Returns::Void
+    } else if row.get("returns_row") {
+        Returns::Row
+    } else {
+        Returns::Scalar
+    };
+
+    Routine {
+        schema: row.get("nspname"),
+        name: row.get("proname"),
+        is_procedure: row.get("is_procedure"),
+        returns,
+        is_volatile: row.get("is_volatile"),
+        return_type: row.get("return_type"),
+        args,
+    }
+}
+
+/// Picks the overload that accepts exactly the given argument names: every argument must
+/// be a named input, and every input without a default must be given.
+pub fn select_overload<'a>(
+    routines: &'a [Routine],
+    args: &Map<String, Value>,
+) -> Result<&'a Routine> {
+    let candidates: Vec<&Routine> = routines
+        .iter()
+        .filter(|routine| {
+            let inputs = || routine.args.iter().filter(|arg| arg.mode.is_input());
+            args.keys()
+                .all(|key| inputs().any(|arg| !arg.name.is_empty() && &arg.name == key))
+                && inputs().all(|arg| arg.has_default || args.contains_key(&arg.name))
+        })
+        .collect();
+
+    match candidates.as_slice() {
+        [routine] => Ok(routine),
+        [] => bail!(
+            "No overload accepts the arguments {:?}",
+            args.keys().collect::<Vec<_>>()
+        ),
+        _ => bail!(
+            "The arguments {:?} match several overloads",
+            args.keys().collect::<Vec<_>>()
+        ),
+    }
+}
+
+/// Builds the statement calling `routine` with named arguments bound as `$1..$n`, and
+/// the arguments in that order.
+pub fn build_call<'a>(
+    routine: &'a Routine,
+    args: &'a Map<String, Value>,
+) -> (String, Vec<(&'a str, &'a Value)>) {
+    let mut arguments = Vec::new();
+    let mut params = Vec::new();
+    for arg in &routine.args {
+        match arg.mode {
+            _ if arg.mode.is_input() => {
+                let Some(value) = args.get(&arg.name) else {
+                    continue;
+                };
+                params.push((arg.name.as_str(), value));
+                let variadic = if arg.mode == ArgMode::Variadic {
+                    "VARIADIC "
+                } else {
+                    ""
+                };
+                arguments.push(format!(
+                    "{}{} => ${}::{}",
+                    variadic,
+                    quote_ident(&arg.name),
+                    params.len(),
+                    arg.type_name
+                ));
+            }
+            // Procedures take their OUT parameters as placeholders in the call
+            ArgMode::Out if routine.is_procedure => {
+                arguments.push(format!("{} => NULL", quote_ident(&arg.name)));
+            }
+            _ => {}
+        }
+    }
+
+    let call = format!(
+        "{}.{}({})",
+        quote_ident(&routine.schema),
+        quote_ident(&routine.name),
+        arguments.join(", ")
+    );
+    let sql = match (routine.is_procedure, routine.returns) {
+        (true, _) => format!("CALL {}", call),
+        (false, Returns::Row | Returns::Set) => format!("SELECT * FROM {}", call),
+        (false, Returns::Scalar | Returns::Void) => format!("SELECT {}", call),
+    };
+    (sql, params)
+}
+
+/// Converts the arguments for the statement's parameters, naming the argument on errors.
+pub fn bind_args(types: &[Type], args: &[(&str, &Value)]) -> Result<Vec<SqlParam>> {
+    types
+        .iter()
+        .zip(args)
+        .map(|(ty, (name, value))| {
+            types::json_to_param(value, ty)
+                .map_err(|e| anyhow!("Invalid value for argument '{}': {}", name, e))
+        })
+        .collect()
+}
+
+/// Converts the rows of a call into the response: rows for set-returning functions, an
+/// object for a single row or a procedure's output parameters, and otherwise the value.
+pub fn result_value(routine: &Routine, rows: &[Row]) -> Value {
+    let object = |row: &Row| Value::Object(types::row_to_json(row));
+    match routine.returns {
+        _ if routine.is_procedure => rows.first().map(object).unwrap_or(Value::Null),
+        Returns::Set => Value::Array(rows.iter().map(object).collect()),
+        Returns::Row => rows.first().map(object).unwrap_or(Value::Null),
+        Returns::Scalar => rows
+            .first()
+            .map(|row| types::column_value(row, 0))
+            .unwrap_or(Value::Null),
+        Returns::Void => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn arg(name: &str, type_name: &str, mode: ArgMode, has_default: bool) -> RoutineArg {
+        RoutineArg {
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+            mode,
+            has_default,
+        }
+    }
+
+    fn function(name: &str, returns: Returns, args: Vec<RoutineArg>) -> Routine {
+        Routine {
+            schema: "api".to_string(),
+            name: name.to_string(),
+            is_procedure: false,
+            returns,
+            is_volatile: true,
+            return_type: "integer".to_string(),
+            args,
+        }
+    }
+
+    fn args(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(parse_name("api.add"), Some(("api", "add")));
+        assert_eq!(parse_name("add"), Some(("public", "add")));
+        assert_eq!(parse_name("pg_catalog.pg_sleep"), None);
+        assert_eq!(parse_name("pg_toast.x"), None);
+        assert_eq!(parse_name("api."), None);
+    }
+
+    #[test]
+    fn test_is_exposed() {
+        assert!(is_exposed(None, "public"));
+        assert!(!is_exposed(None, "api"));
+
+        let config = RpcConfig {
+            enabled: Some(true),
+            schemas: Some(vec!["api".to_string()]),
+        };
+        assert!(is_exposed(Some(&config), "api"));
+        assert!(!is_exposed(Some(&config), "public"));
+    }
+
+    #[test]
+    fn test_select_overload() {
+        let routines = vec![
+            function(
+                "add",
+                Returns::Scalar,
+                vec![
+                    arg("a", "integer", ArgMode::In, false),
+                    arg("b", "integer", ArgMode::In, true),
+                ],
+            ),
+            function(
+                "add",
+                Returns::Scalar,
+                vec![
+                    arg("a", "numeric", ArgMode::In, false),
+                    arg("b", "numeric", ArgMode::In, false),
+                    arg("c", "numeric", ArgMode::In, false),
+                ],
+            ),
+        ];
+
+        let chosen = |value| select_overload(&routines, &args(value)).map(|r| r.args.len());
+        assert_eq!(chosen(json!({"a": 1})).unwrap(), 2);
+        assert_eq!(chosen(json!({"a": 1, "b": 2})).unwrap(), 2);
+        assert_eq!(chosen(json!({"a": 1, "b": 2, "c": 3})).unwrap(), 3);
+        assert!(chosen(json!({"b": 2})).is_err());
+        assert!(chosen(json!({"a": 1, "d": 2})).is_err());
+    }
+
+    #[test]
+    fn test_build_call() {
+        let add = function(
+            "add",
+            Returns::Scalar,
+            vec![
+                arg("a", "integer", ArgMode::In, false),
+                arg("b", "integer", ArgMode::In, true),
+            ],
+        );
+        let both = args(json!({"b": 2, "a": 1}));
+        let (sql, params) = build_call(&add, &both);
+        assert_eq!(
+            sql,
+            "SELECT \"api\".\"add\"(\"a\" => $1::integer, \"b\" => $2::integer)"
+        );
+        assert_eq!(params, vec![("a", &json!(1)), ("b", &json!(2))]);
+
+        let (sql, _) = build_call(&add, &args(json!({"a": 1})));
+        assert_eq!(sql, "SELECT \"api\".\"add\"(\"a\" => $1::integer)");
+
+        let total = function(
+            "total",
+            Returns::Set,
+            vec![
+                arg("xs", "integer[]", ArgMode::Variadic, false),
+                arg("total", "integer", ArgMode::Table, false),
+            ],
+        );
+        let (sql, _) = build_call(&total, &args(json!({"xs": [1, 2]})));
+        assert_eq!(
+            sql,
+            "SELECT * FROM \"api\".\"total\"(VARIADIC \"xs\" => $1::integer[])"
+        );
+
+        let procedure = Routine {
+            is_procedure: true,
+            ..function(
+                "count_users",
+                Returns::Void,
+                vec![
+                    arg("total", "bigint", ArgMode::InOut, true),
+                    arg("max_id", "integer", ArgMode::Out, false),
+                ],
+            )
+        };
+        let none = args(json!({}));
+        let (sql, params) = build_call(&procedure, &none);
+        assert_eq!(sql, "CALL \"api\".\"count_users\"(\"max_id\" => NULL)");
+        assert!(params.is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use tokio_postgres::{Client, GenericClient, Transaction};
+
+use crate::config::RoleMappingConfig;
+use crate::oidc::Claims;
+use crate::sql::quote_ident;
+
+/// Resolves the PostgreSQL role a request runs as.
+///
+/// Returns `Ok(None)` when role mapping is not configured, in which case requests
+/// run as the configured database user.
+pub fn resolve_role(mapping: Option<&RoleMappingConfig>, claims: &Claims) -> Result<Option<String>> {
+    let Some(mapping) = mapping else {
+        return Ok(None);
+    };
+
+    let role = mapping
+        .rules
+        .iter()
+        .find(|rule| claim_matches(claims, &rule.claim, &rule.value))
+        .map(|rule| rule.role.clone())
+        .or_else(|| mapping.default_role.clone())
+        .ok_or_else(|| anyhow!("No role mapping matches user {}", claims.sub))?;
+
+    Ok(Some(role))
+}
+
+/// Checks whether `claim` equals `expected`, or contains it if the claim is an array.
+pub fn claim_matches(claims: &Claims, claim: &str, expected: &str) -> bool {
+    let matches = |value: &Value| match value {
+        Value::String(s) => s == expected,
+        Value::Number(n) => n.to_string() == expected,
+        Value::Bool(b) => expected.parse::<bool>().ok() == Some(*b),
+        _ => false,
+    };
+
+    match claim_value(claims, claim) {
+        Some(Value::Array(items)) => items.iter().any(matches),
+        Some(value) => matches(&value),
+        None => false,
+    }
+}
+
+/// Looks up a claim by name; dotted names address nested objects.
+pub fn claim_value(claims: &Claims, claim: &str) -> Option<Value> {
+    let mut value = serde_json::to_value(claims).ok()?;
+    for part in claim.split('.') {
+        value = value.get_mut(part)?.take();
+    }
+    Some(value)
+}
+
+const READ_ONLY_SAVEPOINT: &str = "proxy_read_only";
+
+/// Starts the transaction a request runs in, switching to the mapped role if any.
+/// Statements that write fail with `read_only_sql_transaction` if `read_only` is set.
+pub async fn begin<'a>(
+    client: &'a mut Client,
+    role: Option<&str>,
+    claims: &Claims,
+    read_only: bool,
+) -> Result<Transaction<'a>, tokio_postgres::Error> {
+    let transaction = client.build_transaction().read_only(read_only).start().await?;
+    configure(&transaction, role, claims).await?;
+    Ok(transaction)
+}
+
+/// Makes the following statements in an open transaction read-only, until `end_read_only`.
+pub async fn begin_read_only<C: GenericClient>(client: &C) -> Result<(), tokio_postgres::Error> {
+    client
+        .batch_execute(&format!(
+            "SAVEPOINT {}; SET TRANSACTION READ ONLY",
+            READ_ONLY_SAVEPOINT
+        ))
+        .await
+}
+
+/// Restores the transaction to its state before `begin_read_only`. Rolling back to the
+/// savepoint also recovers the transaction if a read-only statement failed.
+pub async fn end_read_only<C: GenericClient>(client: &C) -> Result<(), tokio_postgres::Error> {
+    client
+        .batch_execute(&format!(
+            "ROLLBACK TO SAVEPOINT {0}; RELEASE SAVEPOINT {0}",
+            READ_ONLY_SAVEPOINT
+        ))
+        .await
+}
+
+/// Applies the caller's role and claims to the current transaction.
+///
+/// The claims are exposed as the transaction-local settings `request.jwt.claims` (the
+/// full JSON payload) and `request.jwt.claim.sub`, so row-level security policies can
+/// use e.g. `current_setting('request.jwt.claims', true)`.
+pub async fn configure<C: GenericClient>(
+    client: &C,
+    role: Option<&str>,
+    claims: &Claims,
+) -> Result<(), tokio_postgres::Error> {
+    if let Some(role) = role {
+        client
+            .batch_execute(&format!("SET LOCAL ROLE {}", quote_ident(role)))
+            .await?;
+    }
+
+    let claims_json = serde_json::to_string(claims).unwrap_or_else(|_| "{}".to_string());
+    client
+        .execute(
+            "SELECT set_config('request.jwt.claims', $1, true), \
+                    set_config('request.jwt.claim.sub', $2, true)",
+            &[&claims_json, &claims.sub],
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RoleRule;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn claims(other: Value) -> Claims {
+        Claims {
+            sub: "user-1".to_string(),
+            iss: "https://issuer".to_string(),
+            aud: None,
+            exp: 0,
+            iat: 0,
+            other: serde_json::from_value::<HashMap<String, Value>>(other).unwrap(),
+        }
+    }
+
+    fn mapping(default_role: Option<&str>) -> RoleMappingConfig {
+        RoleMappingConfig {
+            rules: vec![
+                RoleRule {
+                    claim: "realm_access.roles".to_string(),
+                    value: "admin".to_string(),
+                    role: "app_admin".to_string(),
+                },
+                RoleRule {
+                    claim: "groups".to_string(),
+                    value: "analysts".to_string(),
+                    role: "analyst".to_string(),
+                },
+            ],
+            default_role: default_role.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_resolve_role() {
+        let analyst = claims(json!({"groups": ["staff", "analysts"]}));
+        let admin = claims(json!({"realm_access": {"roles": ["admin"]}, "groups": ["analysts"]}));
+        let other = claims(json!({"email": "someone@example.com"}));
+
+        assert_eq!(resolve_role(None, &analyst).unwrap(), None);
+        assert_eq!(
+            resolve_role(Some(&mapping(None)), &analyst).unwrap(),
+            Some("analyst".to_string())
+        );
+        assert_eq!(
+            resolve_role(Some(&mapping(None)), &admin).unwrap(),
+            Some("app_admin".to_string())
+        );
+        assert!(resolve_role(Some(&mapping(None)), &other).is_err());
+        assert_eq!(
+            resolve_role(Some(&mapping(Some("web_anon"))), &other).unwrap(),
+            Some("web_anon".to_string())
+        );
+    }
+
+    #[test]
+    fn test_claim_matches_sub() {
+        let claims = claims(json!({}));
+        assert!(claim_matches(&claims, "sub", "user-1"));
+        assert!(!claim_matches(&claims, "sub", "user-2"));
+        assert!(!claim_matches(&claims, "missing", "user-1"));
+    }
+}
@@ -0,0 +1,250 @@
+use axum::body::Body;
+use axum::http::{header::CONTENT_TYPE, StatusCode};
+use axum::response::{IntoResponse, Response};
+use bytes::{Bytes, BytesMut};
+use futures_util::{pin_mut, StreamExt};
+use serde_json::Value;
+use std::io;
+use tokio::sync::{mpsc, oneshot};
+use tokio_postgres::{Client, GenericClient};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::warn;
+
+use crate::format::RowEncoder;
+use crate::oidc::Claims;
+use crate::postgres::{PostgresClient, QueryCanceller};
+use crate::transactions::TransactionGuard;
+use crate::{api_error, db_error, session, types, ApiError};
+
+/// Encoded output is sent to the client in chunks of roughly this size.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Number of chunks buffered between the query task and the response body. Once the
+/// client stops reading, the task stops polling rows and the connection stops reading
+/// from the Postgres socket.
+const CHUNK_BUFFER: usize = 4;
+
+type Chunk = Result<Bytes, io::Error>;
+
+/// The connection a streamed query runs on.
+// Built once per request and moved into the query task, so the size difference is moot
+#[allow(clippy::large_enum_variant)]
+pub enum StreamSession {
+    /// A pooled connection; the query runs in its own read-only transaction as `role`
+    New {
+        client: PostgresClient,
+        role: Option<String>,
+        claims: Claims,
+    },
+    /// An interactive transaction pinned across requests; the query runs read-only
+    Pinned(TransactionGuard),
+}
+
+/// Runs a query and streams its rows through `encoder` as the response body.
+///
+/// The query runs on a task that owns the connection for as long as the body is being
+/// sent. Errors up to and including the first row are returned as a regular error
+/// response; errors after the response has started abort the body, so clients see a
+/// truncated transfer rather than a silently incomplete result.
+pub async fn stream_query(
+    session: StreamSession,
+    sql: String,
+    params: Vec<Value>,
+    encoder: Box<dyn RowEncoder>,
+) -> Result<Response, ApiError> {
+    let content_type = encoder.content_type();
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (body_tx, body_rx) = mpsc::channel(CHUNK_BUFFER);
+
+    tokio::spawn(run_query(session, sql, params, encoder, ready_tx, body_tx));
+
+    match ready_rx.await {
+        Ok(Ok(())) => Ok((
+            [(CONTENT_TYPE, content_type)],
+            Body::from_stream(ReceiverStream::new(body_rx)),
+        )
+            .into_response()),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query execution failed: streaming task ended unexpectedly",
+        )),
+    }
+}
+
+async fn run_query(
+    session: StreamSession,
+    sql: String,
+    params: Vec<Value>,
+    encoder: Box<dyn RowEncoder>,
+    ready: oneshot::Sender<Result<(), ApiError>>,
+    body: mpsc::Sender<Chunk>,
+) {
+    match session {
+        StreamSession::New {
+            mut client,
+            role,
+            claims,
+        } => {
+            let canceller = client.canceller();
+            let transaction = match session::begin(&mut client, role.as_deref(), &claims, true).await {
+                Ok(transaction) => transaction,
+                Err(e) => {
+                    let _ = ready.send(Err(db_error("Failed to start database session", &e)));
+                    return;
+                }
+            };
+
+            let request = StreamRequest {
+                sql: &sql,
+                params: &params,
+                encoder,
+                canceller: &canceller,
+            };
+            let Some(rest) = stream_rows(&transaction, request, ready, &body).await else {
+                return;
+            };
+            if let Err(e) = transaction.commit().await {
+                abort(&body, format!("Query execution failed: {}", e)).await;
+                return;
+            }
+            send_rest(&body, rest).await;
+        }
+        StreamSession::Pinned(guard) => {
+            let client: &Client = &guard;
+            if let Err(e) = session::begin_read_only(client).await {
+                let _ = ready.send(Err(db_error("Query execution failed", &e)));
+                return;
+            }
+
+            let canceller = guard.canceller();
+            let request = StreamRequest {
+                sql: &sql,
+                params: &params,
+                encoder,
+                canceller: &canceller,
+            };
+            let rest = stream_rows(client, request, ready, &body).await;
+            // Runs even if streaming failed, to leave the transaction usable
+            if let Err(e) = session::end_read_only(client).await {
+                abort(&body, format!("Query execution failed: {}", e)).await;
+                return;
+            }
+            if let Some(rest) = rest {
+                send_rest(&body, rest).await;
+            }
+        }
+    }
+}
+
+struct StreamRequest<'a> {
+    sql: &'a str,
+    params: &'a [Value],
+    encoder: Box<dyn RowEncoder>,
+    canceller: &'a QueryCanceller,
+}
+
+/// Executes the query and sends encoded rows to `body`. Returns the final, not yet
+/// sent output once all rows are encoded, or `None` if the stream has been ended
+/// because of an error or a disconnected client.
+async fn stream_rows<C: GenericClient>(
+    client: &C,
+    request: StreamRequest<'_>,
+    ready: oneshot::Sender<Result<(), ApiError>>,
+    body: &mpsc::Sender<Chunk>,
+) -> Option<BytesMut> {
+    let StreamRequest {
+        sql,
+        params,
+        mut encoder,
+        canceller,
+    } = request;
+
+    let statement = match types::prepare(client, sql).await {
+        Ok(statement) => statement,
+        Err(e) => {
+            let _ = ready.send(Err(db_error("Query execution failed", &e)));
+            return None;
+        }
+    };
+
+    let params = match types::bind_params(statement.params(), params) {
+        Ok(params) => params,
+        Err(e) => {
+            warn!("Invalid query parameters: {}", e);
+            let _ = ready.send(Err(api_error(StatusCode::BAD_REQUEST, e.to_string())));
+            return None;
+        }
+    };
+
+    let rows = match client
+        .query_raw(&statement, types::param_refs(&params))
+        .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            let _ = ready.send(Err(db_error("Query execution failed", &e)));
+            return None;
+        }
+    };
+    pin_mut!(rows);
+
+    // Most execution errors surface with the first row, while a proper error response
+    // can still be sent.
+    let first = match rows.next().await.transpose() {
+        Ok(first) => first,
+        Err(e) => {
+            let _ = ready.send(Err(db_error("Query execution failed", &e)));
+            return None;
+        }
+    };
+    if ready.send(Ok(())).is_err() {
+        return None;
+    }
+
+    let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
+    if let Err(e) = encoder.begin(statement.columns(), &mut buf) {
+        abort(body, format!("Failed to encode result: {}", e)).await;
+        return None;
+    }
+
+    let mut next = first.map(Ok);
+    while let Some(row) = next {
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                abort(body, format!("Query execution failed: {}", e)).await;
+                return None;
+            }
+        };
+        if let Err(e) = encoder.encode_row(&row, &mut buf) {
+            abort(body, format!("Failed to encode result: {}", e)).await;
+            return None;
+        }
+        if buf.len() >= CHUNK_SIZE && body.send(Ok(buf.split().freeze())).await.is_err() {
+            // The client went away. Cancel the statement so the server stops producing
+            // rows. The connection is closed rather than reused once released, so a
+            // late cancel request cannot hit another statement.
+            canceller.cancel().await;
+            return None;
+        }
+        next = rows.next().await;
+    }
+
+    if let Err(e) = encoder.finish(&mut buf) {
+        abort(body, format!("Failed to encode result: {}", e)).await;
+        return None;
+    }
+    Some(buf)
+}
+
+async fn send_rest(body: &mpsc::Sender<Chunk>, rest: BytesMut) {
+    if !rest.is_empty() {
+        let _ = body.send(Ok(rest.freeze())).await;
+    }
+}
+
+/// Ends the response body with an error, which aborts the transfer.
+async fn abort(body: &mpsc::Sender<Chunk>, message: String) {
+    warn!("{}", message);
+    let _ = body.send(Err(io::Error::other(message))).await;
+}
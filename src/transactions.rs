@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedMutexGuard;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::TransactionConfig;
+use crate::oidc::Claims;
+use crate::postgres::PostgresClient;
+use crate::session;
+
+pub const TRANSACTION_ID_HEADER: &str = "x-transaction-id";
+
+const DEFAULT_IDLE_TIMEOUT_SECONDS: u64 = 60;
+const DEFAULT_MAX_PER_USER: usize = 5;
+const REAPER_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Transactions that span several HTTP requests, each pinned to its own connection.
+#[derive(Clone)]
+pub struct TransactionRegistry {
+    inner: Arc<RegistryInner>,
+}
+
+struct RegistryInner {
+    transactions: Mutex<HashMap<Uuid, Arc<PinnedTransaction>>>,
+    idle_timeout: Duration,
+    max_per_user: usize,
+}
+
+/// Why a transaction could not be started.
+#[derive(Debug)]
+pub enum BeginError {
+    /// The user already has the maximum number of open transactions
+    TooMany,
+    Database(tokio_postgres::Error),
+}
+
+/// An open transaction and the connection it runs on.
+pub struct PinnedTransaction {
+    owner: String,
+    conn: Arc<tokio::sync::Mutex<PinnedConnection>>,
+}
+
+struct PinnedConnection {
+    client: PostgresClient,
+    last_used: Instant,
+    /// Set once the transaction has been committed or rolled back
+    closed: bool,
+}
+
+/// Exclusive access to a pinned transaction's connection for one request.
+pub struct TransactionGuard(OwnedMutexGuard<PinnedConnection>);
+
+impl TransactionRegistry {
+    pub fn new(config: Option<&TransactionConfig>) -> Self {
+        let idle_timeout = Duration::from_secs(
+            config
+                .and_then(|c| c.idle_timeout_seconds)
+                .unwrap_or(DEFAULT_IDLE_TIMEOUT_SECONDS),
+        );
+        let max_per_user = config
+            .and_then(|c| c.max_per_user)
+            .unwrap_or(DEFAULT_MAX_PER_USER);
+        let inner = Arc::new(RegistryInner {
+            transactions: Mutex::new(HashMap::new()),
+            idle_timeout,
+            max_per_user,
+        });
+
+        let weak = Arc::downgrade(&inner);
+        tokio::spawn(async move {
+            run_reaper(weak).await;
+        });
+
+        Self { inner }
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.inner.idle_timeout
+    }
+
+    /// Whether `owner` may start another transaction. Checked before taking a connection
+    /// from the pool; `begin` checks again when registering the transaction.
+    pub fn has_capacity(&self, owner: &str) -> bool {
+        let transactions = self.inner.transactions.lock().unwrap();
+        self.inner.has_capacity(&transactions, owner)
+    }
+
+    /// Starts a transaction on `client` and registers it under a new ID.
+    pub async fn begin(
+        &self,
+        client: PostgresClient,
+        role: Option<&str>,
+        claims: &Claims,
+    ) -> Result<Uuid, BeginError> {
+        client
+            .batch_execute("BEGIN")
+            .await
+            .map_err(BeginError::Database)?;
+        if let Err(e) = session::configure(&*client, role, claims).await {
+            // Leave the connection clean so the pool can reuse it
+            let _ = client.batch_execute("ROLLBACK").await;
+            return Err(BeginError::Database(e));
+        }
+
+        let id = Uuid::new_v4();
+        let transaction = Arc::new(PinnedTransaction {
+            owner: claims.sub.clone(),
+            conn: Arc::new(tokio::sync::Mutex::new(PinnedConnection {
+                client,
+                last_used: Instant::now(),
+                closed: false,
+            })),
+        });
+        {
+            let mut transactions = self.inner.transactions.lock().unwrap();
+            if self.inner.has_capacity(&transactions, &claims.sub) {
+                transactions.insert(id, transaction);
+                return Ok(id);
+            }
+        }
+
+        // Another request of the same user got there first
+        let _ = transaction.rollback().await;
+        Err(BeginError::TooMany)
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Arc<PinnedTransaction>> {
+        self.inner.transactions.lock().unwrap().get(id).cloned()
+    }
+
+    /// Removes a transaction so no further requests can use it.
+    pub fn remove(&self, id: &Uuid) -> Option<Arc<PinnedTransaction>> {
+        self.inner.transactions.lock().unwrap().remove(id)
+    }
+}
+
+impl RegistryInner {
+    fn has_capacity(
+        &self,
+        transactions: &HashMap<Uuid, Arc<PinnedTransaction>>,
+        owner: &str,
+    ) -> bool {
+        let open = transactions
+            .values()
+            .filter(|transaction| transaction.owner == owner)
+            .count();
+        open < self.max_per_user
+    }
+}
+
+impl PinnedTransaction {
+    /// The `sub` of the user who started the transaction.
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// Waits for exclusive use of the connection. Returns `None` if the transaction has
+    /// ended in the meantime.
+    pub async fn lock(&self) -> Option<TransactionGuard> {
+        let conn = self.conn.clone().lock_owned().await;
+        if conn.closed {
+            return None;
+        }
+        Some(TransactionGuard(conn))
+    }
+
+    /// Commits the transaction. A transaction aborted by a failed statement is rolled
+    /// back instead and the error that aborted it is returned.
+    pub async fn commit(&self) -> Result<(), tokio_postgres::Error> {
+        let mut conn = self.conn.lock().await;
+        conn.closed = true;
+
+        // COMMIT in an aborted transaction silently rolls back, so check first
+        if let Err(e) = conn.client.simple_query("SELECT 1").await {
+            let _ = conn.client.batch_execute("ROLLBACK").await;
+            return Err(e);
+        }
+        conn.client.batch_execute("COMMIT").await
+    }
+
+    pub async fn rollback(&self) -> Result<(), tokio_postgres::Error> {
+        let mut conn = self.conn.lock().await;
+        conn.closed = true;
+        conn.client.batch_execute("ROLLBACK").await
+    }
+}
+
+impl std::ops::Deref for TransactionGuard {
+    type Target = PostgresClient;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0.client
+    }
+}
+
+impl std::ops::DerefMut for TransactionGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0.client
+    }
+}
+
+impl Drop for TransactionGuard {
+    fn drop(&mut self) {
+        // The idle timeout counts from the end of the last request
+        self.0.last_used = Instant::now();
+    }
+}
+
+/// Rolls back transactions that have not been used for `idle_timeout`.
+async fn run_reaper(registry: Weak<RegistryInner>) {
+    let mut interval = tokio::time::interval(REAPER_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(registry) = registry.upgrade() else {
+            return;
+        };
+
+        let expired: Vec<(Uuid, Arc<PinnedTransaction>)> = {
+            let mut transactions = registry.transactions.lock().unwrap();
+            let ids: Vec<Uuid> = transactions
+                .iter()
+                .filter(|(_, transaction)| {
+                    // Transactions serving a request are never idle
+                    transaction
+                        .conn
+                        .try_lock()
+                        .is_ok_and(|conn| conn.last_used.elapsed() >= registry.idle_timeout)
+                })
+                .map(|(id, _)| *id)
+                .collect();
+            ids.into_iter()
+                .filter_map(|id| transactions.remove(&id).map(|t| (id, t)))
+                .collect()
+        };
+
+        for (id, transaction) in expired {
+            info!(
+                "Rolling back transaction {} of user {} after idle timeout",
+                id, transaction.owner
+            );
+            tokio::spawn(async move {
+                if let Err(e) = transaction.rollback().await {
+                    warn!("Failed to roll back idle transaction {}: {}", id, e);
+                }
+            });
+        }
+    }
+}
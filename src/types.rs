@@ -0,0 +1,580 @@
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::BytesMut;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tokio_postgres::types::{to_sql_checked, Format, FromSql, IsNull, Kind, ToSql, Type};
+use tokio_postgres::{GenericClient, Row, Statement};
+use tracing::warn;
+
+use crate::sql::quote_ident;
+
+const TEXT_COLUMNS_SAVEPOINT: &str = "proxy_text_columns";
+
+/// Types `decode_value` maps to JSON; results of any other type are read as text.
+const DECODED_TYPES: &[Type] = &[
+    Type::BOOL,
+    Type::INT2,
+    Type::INT4,
+    Type::INT8,
+    Type::OID,
+    Type::CHAR,
+    Type::FLOAT4,
+    Type::FLOAT8,
+    Type::NUMERIC,
+    Type::TEXT,
+    Type::VARCHAR,
+    Type::BPCHAR,
+    Type::NAME,
+    Type::UNKNOWN,
+    Type::XML,
+    Type::JSON,
+    Type::JSONB,
+    Type::UUID,
+    Type::TIMESTAMPTZ,
+    Type::TIMESTAMP,
+    Type::DATE,
+    Type::TIME,
+    Type::BYTEA,
+    Type::INET,
+    Type::CIDR,
+];
+
+/// A query parameter converted from JSON into the type Postgres inferred for it.
+#[derive(Debug)]
+pub enum SqlParam {
+    Null,
+    Bool(bool),
+    Int2(i16),
+    Int4(i32),
+    Int8(i64),
+    Oid(u32),
+    Float4(f32),
+    Float8(f64),
+    Text(String),
+    Json(Value),
+    Bytea(Vec<u8>),
+    /// Sent in the text format and parsed by the server. Used for numeric,
+    /// date/time, uuid, arrays and any type without a binary mapping here.
+    Literal(String),
+}
+
+impl ToSql for SqlParam {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        match self {
+            SqlParam::Null => Ok(IsNull::Yes),
+            SqlParam::Bool(v) => v.to_sql(ty, out),
+            SqlParam::Int2(v) => v.to_sql(ty, out),
+            SqlParam::Int4(v) => v.to_sql(ty, out),
+            SqlParam::Int8(v) => v.to_sql(ty, out),
+            SqlParam::Oid(v) => v.to_sql(ty, out),
+            SqlParam::Float4(v) => v.to_sql(ty, out),
+            SqlParam::Float8(v) => v.to_sql(ty, out),
+            SqlParam::Text(v) | SqlParam::Literal(v) => v.to_sql(ty, out),
+            SqlParam::Json(v) => v.to_sql(ty, out),
+            SqlParam::Bytea(v) => v.to_sql(ty, out),
+        }
+    }
+
+    // Conversion has already been checked against the statement's parameter types.
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    fn encode_format(&self, _ty: &Type) -> Format {
+        match self {
+            SqlParam::Literal(_) => Format::Text,
+            _ => Format::Binary,
+        }
+    }
+
+    to_sql_checked!();
+}
+
+/// Converts the JSON `params` of a request into values for the statement's parameters.
+pub fn bind_params(types: &[Type], values: &[Value]) -> Result<Vec<SqlParam>> {
+    if types.len() != values.len() {
+        bail!(
+            "Statement expects {} parameters but {} were provided",
+            types.len(),
+            values.len()
+        );
+    }
+
+    types
+        .iter()
+        .zip(values)
+        .enumerate()
+        .map(|(i, (ty, value))| {
+            json_to_param(value, ty).map_err(|e| anyhow!("Invalid value for parameter ${}: {}", i + 1, e))
+        })
+        .collect()
+}
+
+pub fn param_refs(params: &[SqlParam]) -> Vec<&(dyn ToSql + Sync)> {
+    params.iter().map(|p| p as &(dyn ToSql + Sync)).collect()
+}
+
+pub fn json_to_param(value: &Value, ty: &Type) -> Result<SqlParam> {
+    if value.is_null() {
+        return Ok(SqlParam::Null);
+    }
+
+    match ty.kind() {
+        Kind::Domain(inner) => return json_to_param(value, inner),
+        Kind::Array(_) => {
+            if !value.is_array() {
+                bail!("expected an array for type {}", ty);
+            }
+            return Ok(SqlParam::Literal(array_literal(value)));
+        }
+        _ => {}
+    }
+
+    let param = match *ty {
+        Type::BOOL => SqlParam::Bool(match value {
+            Value::Bool(b) => *b,
+            Value::String(s) => parse_bool(s)?,
+            _ => bail!("expected a boolean"),
+        }),
+        Type::INT2 => SqlParam::Int2(i16::try_from(json_to_i64(value)?)?),
+        Type::INT4 => SqlParam::Int4(i32::try_from(json_to_i64(value)?)?),
+        Type::INT8 => SqlParam::Int8(json_to_i64(value)?),
+        Type::OID => SqlParam::Oid(u32::try_from(json_to_i64(value)?)?),
+        Type::FLOAT4 => SqlParam::Float4(json_to_f64(value)? as f32),
+        Type::FLOAT8 => SqlParam::Float8(json_to_f64(value)?),
+        Type::NUMERIC => match value {
+            Value::Number(n) => SqlParam::Literal(n.to_string()),
+            Value::String(s) => SqlParam::Literal(s.clone()),
+            _ => bail!("expected a number or numeric string"),
+        },
+        Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME | Type::UNKNOWN => match value {
+            Value::String(s) => SqlParam::Text(s.clone()),
+            Value::Number(n) => SqlParam::Text(n.to_string()),
+            Value::Bool(b) => SqlParam::Text(b.to_string()),
+            _ => bail!("expected a string"),
+        },
+        Type::JSON | Type::JSONB => SqlParam::Json(value.clone()),
+        Type::BYTEA => match value {
+            Value::String(s) => SqlParam::Bytea(BASE64.decode(s)?),
+            _ => bail!("expected a base64-encoded string"),
+        },
+        // Everything else (uuid, date/time types, enums, inet, ...) goes through
+        // the server's own text input functions.
+        _ => match value {
+            Value::String(s) => SqlParam::Literal(s.clone()),
+            Value::Number(n) => SqlParam::Literal(n.to_string()),
+            Value::Bool(b) => SqlParam::Literal(b.to_string()),
+            _ => bail!("expected a string for type {}", ty),
+        },
+    };
+
+    Ok(param)
+}
+
+fn parse_bool(s: &str) -> Result<bool> {
+    match s.to_ascii_lowercase().as_str() {
+        "true" | "t" | "yes" | "on" | "1" => Ok(true),
+        "false" | "f" | "no" | "off" | "0" => Ok(false),
+        _ => bail!("expected a boolean, got {:?}", s),
+    }
+}
+
+fn json_to_i64(value: &Value) -> Result<i64> {
+    match value {
+        Value::Number(n) => n.as_i64().ok_or_else(|| anyhow!("expected an integer, got {}", n)),
+        Value::String(s) => s
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("expected an integer, got {:?}", s)),
+        _ => bail!("expected an integer"),
+    }
+}
+
+fn json_to_f64(value: &Value) -> Result<f64> {
+    match value {
+        Value::Number(n) => n.as_f64().ok_or_else(|| anyhow!("expected a number, got {}", n)),
+        Value::String(s) => s
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("expected a number, got {:?}", s)),
+        _ => bail!("expected a number"),
+    }
+}
+
+/// Renders a JSON array as a Postgres array literal, e.g. `{"a","b",NULL}`.
+fn array_literal(value: &Value) -> String {
+    match value {
+        Value::Array(items) => {
+            let elements: Vec<String> = items.iter().map(array_literal).collect();
+            format!("{{{}}}", elements.join(","))
+        }
+        Value::Null => "NULL".to_string(),
+        Value::String(s) => quote_array_element(s),
+        other => quote_array_element(&other.to_string()),
+    }
+}
+
+fn quote_array_element(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Prepares `sql` for a client inside a transaction. If a result column has a type
+/// without a JSON mapping, such as `interval` or a range, the statement is prepared again
+/// with that column cast to text, so its text representation is returned.
+pub async fn prepare<C: GenericClient>(
+    client: &C,
+    sql: &str,
+) -> Result<Statement, tokio_postgres::Error> {
+    let statement = client.prepare(sql).await?;
+    let as_text: Vec<bool> = statement
+        .columns()
+        .iter()
+        .map(|column| !is_decoded(column.type_()))
+        .collect();
+    if !as_text.contains(&true) {
+        return Ok(statement);
+    }
+
+    let names: Vec<&str> = statement.columns().iter().map(|c| c.name()).collect();
+    let wrapped = text_columns_sql(sql, &names, &as_text);
+    // Not every statement can be used in WITH, e.g. EXPLAIN or SHOW; the savepoint keeps
+    // a failed attempt from aborting the transaction
+    client
+        .batch_execute(&format!("SAVEPOINT {}", TEXT_COLUMNS_SAVEPOINT))
+        .await?;
+    match client.prepare(&wrapped).await {
+        Ok(wrapped) => {
+            client
+                .batch_execute(&format!("RELEASE SAVEPOINT {}", TEXT_COLUMNS_SAVEPOINT))
+                .await?;
+            Ok(wrapped)
+        }
+        Err(e) => {
+            client
+                .batch_execute(&format!(
+                    "ROLLBACK TO SAVEPOINT {0}; RELEASE SAVEPOINT {0}",
+                    TEXT_COLUMNS_SAVEPOINT
+                ))
+                .await?;
+            warn!(
+                "Cannot read columns without a JSON mapping as text, they are returned as null: {}",
+                e
+            );
+            Ok(statement)
+        }
+    }
+}
+
+/// Whether `decode_value` maps values of `ty` to JSON.
+fn is_decoded(ty: &Type) -> bool {
+    match ty.kind() {
+        Kind::Domain(inner) => is_decoded(inner),
+        Kind::Array(element) => is_decoded(element),
+        Kind::Enum(_) => true,
+        _ => DECODED_TYPES.contains(ty),
+    }
+}
+
+/// Wraps `sql` in a CTE and selects its columns under their original names, casting
+/// those marked in `as_text`. The CTE's column list renames them by position, so
+/// duplicate names are no problem.
+fn text_columns_sql(sql: &str, names: &[&str], as_text: &[bool]) -> String {
+    let aliases: Vec<String> = (1..=names.len()).map(|i| format!("c{}", i)).collect();
+    let columns: Vec<String> = aliases
+        .iter()
+        .zip(names.iter().zip(as_text))
+        .map(|(alias, (name, &as_text))| {
+            let cast = if as_text { "::text" } else { "" };
+            format!("q.{}{} AS {}", alias, cast, quote_ident(name))
+        })
+        .collect();
+    // The newline ends a trailing line comment in `sql`
+    format!(
+        "WITH q({}) AS ({}\n) SELECT {} FROM q",
+        aliases.join(", "),
+        sql.trim_end().trim_end_matches(';').trim_end(),
+        columns.join(", ")
+    )
+}
+
+/// A result column decoded into JSON according to its Postgres type.
+pub struct PgValue(pub Value);
+
+impl<'a> FromSql<'a> for PgValue {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        decode_value(ty, raw).map(PgValue)
+    }
+
+    fn from_sql_null(_ty: &Type) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(PgValue(Value::Null))
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+}
+
+/// Converts a result row into a JSON object keyed by column name.
+pub fn row_to_json(row: &Row) -> Map<String, Value> {
+    let mut json_row = Map::new();
+    for (i, column) in row.columns().iter().enumerate() {
+        json_row.insert(column.name().to_string(), column_value(row, i));
+    }
+    json_row
+}
+
+/// Converts a result row into a JSON array of values in column order.
+pub fn row_to_array(row: &Row) -> Vec<Value> {
+    (0..row.len()).map(|i| column_value(row, i)).collect()
+}
+
+pub fn column_value(row: &Row, idx: usize) -> Value {
+    match row.try_get::<_, PgValue>(idx) {
+        Ok(PgValue(value)) => value,
+        Err(e) => {
+            let column = &row.columns()[idx];
+            warn!(
+                "Failed to decode column {} of type {}: {}",
+                column.name(),
+                column.type_(),
+                e
+            );
+            Value::Null
+        }
+    }
+}
+
+fn decode_value(ty: &Type, raw: &[u8]) -> Result<Value, Box<dyn Error + Sync + Send>> {
+    match ty.kind() {
+        Kind::Domain(inner) => return decode_value(inner, raw),
+        Kind::Array(_) => {
+            let items = Vec::<PgValue>::from_sql(ty, raw)?;
+            return Ok(Value::Array(items.into_iter().map(|v| v.0).collect()));
+        }
+        Kind::Enum(_) => return Ok(Value::String(std::str::from_utf8(raw)?.to_string())),
+        _ => {}
+    }
+
+    let value = match *ty {
+        Type::BOOL => Value::Bool(bool::from_sql(ty, raw)?),
+        Type::INT2 => i16::from_sql(ty, raw)?.into(),
+        Type::INT4 => i32::from_sql(ty, raw)?.into(),
+        Type::INT8 => i64::from_sql(ty, raw)?.into(),
+        Type::OID => u32::from_sql(ty, raw)?.into(),
+        Type::CHAR => Value::String((i8::from_sql(ty, raw)? as u8 as char).to_string()),
+        Type::FLOAT4 => float_value(f32::from_sql(ty, raw)? as f64),
+        Type::FLOAT8 => float_value(f64::from_sql(ty, raw)?),
+        // Numerics are returned as strings so no precision is lost.
+        Type::NUMERIC => Value::String(decode_numeric(raw)?),
+        Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME | Type::UNKNOWN | Type::XML => {
+            Value::String(std::str::from_utf8(raw)?.to_string())
+        }
+        Type::JSON | Type::JSONB => Value::from_sql(ty, raw)?,
+        Type::UUID => Value::String(uuid::Uuid::from_sql(ty, raw)?.to_string()),
+        Type::TIMESTAMPTZ => Value::String(
+            DateTime::<Utc>::from_sql(ty, raw)?.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        ),
+        Type::TIMESTAMP => Value::String(
+            NaiveDateTime::from_sql(ty, raw)?
+                .format("%Y-%m-%dT%H:%M:%S%.f")
+                .to_string(),
+        ),
+        Type::DATE => Value::String(NaiveDate::from_sql(ty, raw)?.to_string()),
+        Type::TIME => Value::String(NaiveTime::from_sql(ty, raw)?.to_string()),
+        Type::BYTEA => Value::String(BASE64.encode(raw)),
+        Type::INET | Type::CIDR => Value::String(decode_inet(raw)?),
+        // Only reached if `prepare` could not read the column as text
+        _ => return Err(format!("no JSON mapping for type {}", ty).into()),
+    };
+
+    Ok(value)
+}
+
+fn float_value(v: f64) -> Value {
+    serde_json::Number::from_f64(v)
+        .map(Value::Number)
+        .unwrap_or_else(|| {
+            let s = if v.is_nan() {
+                "NaN"
+            } else if v > 0.0 {
+                "Infinity"
+            } else {
+                "-Infinity"
+            };
+            Value::String(s.to_string())
+        })
+}
+
+fn read_i16(raw: &[u8], offset: usize) -> Result<i16, Box<dyn Error + Sync + Send>> {
+    raw.get(offset..offset + 2)
+        .map(|b| i16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| "invalid numeric buffer".into())
+}
+
+/// Decodes the binary `numeric` format into its exact decimal string.
+fn decode_numeric(raw: &[u8]) -> Result<String, Box<dyn Error + Sync + Send>> {
+    let ndigits = read_i16(raw, 0)? as usize;
+    let weight = read_i16(raw, 2)? as i32;
+    let sign = read_i16(raw, 4)? as u16;
+    let dscale = read_i16(raw, 6)? as usize;
+
+    match sign {
+        0x0000 | 0x4000 => {}
+        0xC000 => return Ok("NaN".to_string()),
+        0xD000 => return Ok("Infinity".to_string()),
+        0xF000 => return Ok("-Infinity".to_string()),
+        _ => return Err("invalid numeric sign".into()),
+    }
+
+    let digits = (0..ndigits)
+        .map(|i| read_i16(raw, 8 + i * 2))
+        .collect::<Result<Vec<_>, _>>()?;
+    let digit = |pos: i32| -> i16 {
+        if pos >= 0 && (pos as usize) < digits.len() {
+            digits[pos as usize]
+        } else {
+            0
+        }
+    };
+
+    let mut result = String::new();
+    if sign == 0x4000 {
+        result.push('-');
+    }
+
+    if weight < 0 {
+        result.push('0');
+    } else {
+        result.push_str(&digit(0).to_string());
+        for pos in 1..=weight {
+            result.push_str(&format!("{:04}", digit(pos)));
+        }
+    }
+
+    if dscale > 0 {
+        let mut fraction = String::new();
+        let mut pos = weight + 1;
+        while fraction.len() < dscale {
+            fraction.push_str(&format!("{:04}", digit(pos)));
+            pos += 1;
+        }
+        fraction.truncate(dscale);
+        result.push('.');
+        result.push_str(&fraction);
+    }
+
+    Ok(result)
+}
+
+fn decode_inet(raw: &[u8]) -> Result<String, Box<dyn Error + Sync + Send>> {
+    if raw.len() < 4 {
+        return Err("invalid inet buffer".into());
+    }
+    let (family, bits, addr) = (raw[0], raw[1], &raw[4..]);
+    let (ip, max_bits) = match (family, addr.len()) {
+        (2, 4) => (IpAddr::V4(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3])), 32),
+        (3, 16) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(addr);
+            (IpAddr::V6(Ipv6Addr::from(octets)), 128)
+        }
+        _ => return Err("invalid inet address family".into()),
+    };
+    if bits == max_bits {
+        Ok(ip.to_string())
+    } else {
+        Ok(format!("{}/{}", ip, bits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_json_to_param_integers() {
+        assert!(matches!(json_to_param(&json!(42), &Type::INT4).unwrap(), SqlParam::Int4(42)));
+        assert!(matches!(json_to_param(&json!("7"), &Type::INT8).unwrap(), SqlParam::Int8(7)));
+        assert!(json_to_param(&json!(70000), &Type::INT2).is_err());
+        assert!(json_to_param(&json!(1.5), &Type::INT4).is_err());
+        assert!(matches!(json_to_param(&json!(null), &Type::INT4).unwrap(), SqlParam::Null));
+    }
+
+    #[test]
+    fn test_array_literal() {
+        let param = json_to_param(&json!(["a", "b\"c", null, ["x"]]), &Type::TEXT_ARRAY).unwrap();
+        match param {
+            SqlParam::Literal(s) => assert_eq!(s, r#"{"a","b\"c",NULL,{"x"}}"#),
+            other => panic!("unexpected param: {:?}", other),
+        }
+    }
+
+    fn encode_numeric(ndigits: i16, weight: i16, sign: u16, dscale: i16, digits: &[i16]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&ndigits.to_be_bytes());
+        raw.extend_from_slice(&weight.to_be_bytes());
+        raw.extend_from_slice(&sign.to_be_bytes());
+        raw.extend_from_slice(&dscale.to_be_bytes());
+        for d in digits {
+            raw.extend_from_slice(&d.to_be_bytes());
+        }
+        raw
+    }
+
+    #[test]
+    fn test_decode_numeric() {
+        // 12345.678
+        let raw = encode_numeric(3, 1, 0x0000, 3, &[1, 2345, 6780]);
+        assert_eq!(decode_numeric(&raw).unwrap(), "12345.678");
+        // -0.0012
+        let raw = encode_numeric(1, -1, 0x4000, 4, &[12]);
+        assert_eq!(decode_numeric(&raw).unwrap(), "-0.0012");
+        // 100000000 (trailing zero groups are omitted on the wire)
+        let raw = encode_numeric(1, 2, 0x0000, 0, &[1]);
+        assert_eq!(decode_numeric(&raw).unwrap(), "100000000");
+        // 0.00
+        let raw = encode_numeric(0, 0, 0x0000, 2, &[]);
+        assert_eq!(decode_numeric(&raw).unwrap(), "0.00");
+        let raw = encode_numeric(0, 0, 0xC000, 0, &[]);
+        assert_eq!(decode_numeric(&raw).unwrap(), "NaN");
+    }
+
+    #[test]
+    fn test_float_value() {
+        assert_eq!(float_value(1.5), json!(1.5));
+        assert_eq!(float_value(f64::NAN), json!("NaN"));
+        assert_eq!(float_value(f64::NEG_INFINITY), json!("-Infinity"));
+    }
+
+    #[test]
+    fn test_is_decoded() {
+        assert!(is_decoded(&Type::INT4));
+        assert!(is_decoded(&Type::TIMESTAMPTZ_ARRAY));
+        assert!(!is_decoded(&Type::INTERVAL));
+        assert!(!is_decoded(&Type::INTERVAL_ARRAY));
+        assert!(!is_decoded(&Type::INT4_RANGE));
+        assert!(!is_decoded(&Type::TSTZ_RANGE));
+    }
+
+    #[test]
+    fn test_text_columns_sql() {
+        assert_eq!(
+            text_columns_sql(
+                "SELECT id, '1 day'::interval AS id, int4range(1, 5) -- range;\n;",
+                &["id", "id", "int4range"],
+                &[false, true, true],
+            ),
+            "WITH q(c1, c2, c3) AS (SELECT id, '1 day'::interval AS id, int4range(1, 5) -- range;\n) \
+             SELECT q.c1 AS \"id\", q.c2::text AS \"id\", q.c3::text AS \"int4range\" FROM q"
+        );
+    }
+
+    #[test]
+    fn test_bind_params_count_mismatch() {
+        assert!(bind_params(&[Type::INT4], &[]).is_err());
+    }
+}